@@ -0,0 +1,241 @@
+//! `--backtrace`, the call stacks of the traced process unwound with the CFI of the binary
+//!
+//! The frames are unwound with `.eh_frame`, or `.debug_frame` for the code it doesn't cover,
+//! so functions built without frame pointers are unwound too. Code without any CFI falls back
+//! to the frame pointer chain.
+
+use std::fmt;
+use std::rc::Rc;
+
+use gimli::{CfaRule, RegisterRule, UninitializedUnwindContext, UnwindSection, X86_64};
+use object::{Object, ObjectSection, SectionKind};
+
+use crate::defs::{ProcessInfo, Registers, Result};
+use crate::function::Function;
+use crate::lines::{LineTable, SourceLocation};
+use crate::process_ext::{register_mut, ProcessExt};
+
+/// Deepest backtrace unwound, recursion would otherwise print thousands of frames
+const MAX_FRAMES: usize = 64;
+
+type Endian = gimli::LittleEndian;
+
+/// The CFA rule of a frame and the offsets from the CFA of the registers saved by it
+type Rules<'a> = (
+    CfaRule<gimli::EndianSlice<'a, Endian>>,
+    Vec<(gimli::Register, i64)>,
+);
+
+/// Unwinds and symbolizes the stack of the traced process
+pub struct Backtracer {
+    eh_frame: Option<(Vec<u8>, gimli::BaseAddresses)>,
+    debug_frame: Option<Vec<u8>>,
+    /// start and name of every function, sorted
+    symbols: Vec<(u64, String)>,
+    lines: Rc<LineTable>,
+    /// the executable code of the binary, relocated
+    text: (u64, u64),
+    /// where the binary was loaded
+    base: u64,
+}
+
+/// A frame of a backtrace, the innermost first
+#[derive(Debug)]
+pub struct StackFrame {
+    pub pc: u64,
+    /// the function `pc` is in and the offset into it
+    pub function: Option<(String, u64)>,
+    /// the line of `pc`, for callers the line of the call
+    pub location: Option<SourceLocation>,
+}
+
+impl Backtracer {
+    /// Reads the CFI of the binary, `functions` are relocated by `base` and `lines` are not
+    pub fn new(
+        obj: &object::File,
+        base: u64,
+        functions: &[Function],
+        lines: Rc<LineTable>,
+    ) -> Result<Self> {
+        let eh_frame = match obj.section_by_name(".eh_frame") {
+            Some(section) => {
+                let mut bases = gimli::BaseAddresses::default().set_eh_frame(section.address());
+                if let Some(text) = obj.section_by_name(".text") {
+                    bases = bases.set_text(text.address());
+                }
+                if let Some(got) = obj.section_by_name(".got") {
+                    bases = bases.set_got(got.address());
+                }
+                Some((section.data()?.to_vec(), bases))
+            }
+            None => None,
+        };
+        let debug_frame = match obj.section_by_name(".debug_frame") {
+            Some(section) => Some(section.uncompressed_data()?.into_owned()),
+            None => None,
+        };
+
+        let mut symbols: Vec<_> = functions
+            .iter()
+            .filter(|f| !f.inlined)
+            .map(|f| (f.address, f.name.clone()))
+            .collect();
+        symbols.sort_unstable();
+        symbols.dedup_by_key(|(address, _)| *address);
+
+        let text = obj
+            .sections()
+            .filter(|section| section.kind() == SectionKind::Text)
+            .fold((u64::MAX, 0), |(start, end), section| {
+                (
+                    start.min(section.address()),
+                    end.max(section.address() + section.size()),
+                )
+            });
+
+        Ok(Self {
+            eh_frame,
+            debug_frame,
+            symbols,
+            lines,
+            text: (text.0 + base, text.1 + base),
+            base,
+        })
+    }
+
+    /// Unwinds the stack of the stopped process
+    pub fn backtrace(&self, process: &dyn ProcessInfo) -> Result<Vec<StackFrame>> {
+        let mut registers = process.get_registers()?;
+        let mut frames = vec![];
+        while frames.len() < MAX_FRAMES {
+            let pc = registers.rip;
+            // a caller is inside the call instruction, not at the instruction after it
+            let lookup = if frames.is_empty() { pc } else { pc - 1 };
+            frames.push(self.symbolize(pc, lookup));
+            match self.unwind(process, &registers, lookup) {
+                Some(caller) if caller.rip > 1 && caller.rsp > registers.rsp => registers = caller,
+                _ => break,
+            }
+        }
+        Ok(frames)
+    }
+
+    /// The registers of the caller of the frame with `registers`, `None` if it can't be found
+    fn unwind(
+        &self,
+        process: &dyn ProcessInfo,
+        registers: &Registers,
+        pc: u64,
+    ) -> Option<Registers> {
+        let in_binary = (self.text.0..self.text.1).contains(&pc);
+        let (cfa, saved) = match in_binary.then(|| self.rules(pc - self.base)).flatten() {
+            Some((CfaRule::RegisterAndOffset { register, offset }, saved)) => {
+                let value = process.get_register_value(registers, register).ok()?;
+                ((value as i64 + offset) as u64, saved)
+            }
+            Some((CfaRule::Expression(_), _)) => return None,
+            // without CFI, assume a frame pointer was set up: rbp points at the caller's rbp
+            // with the return address above it
+            None if registers.rbp > registers.rsp => (
+                registers.rbp + 16,
+                vec![(X86_64::RBP, -16), (X86_64::RA, -8)],
+            ),
+            None => return None,
+        };
+
+        let mut caller = *registers;
+        caller.rsp = cfa;
+        let mut has_ra = false;
+        for (register, offset) in saved {
+            let value = process.read_u64_at((cfa as i64 + offset) as u64).ok()?;
+            if register == X86_64::RA {
+                caller.rip = value;
+                has_ra = true;
+            } else if let Some(slot) = register_mut(&mut caller, register) {
+                *slot = value;
+            }
+        }
+        if has_ra {
+            Some(caller)
+        } else {
+            None
+        }
+    }
+
+    /// The CFA rule at `address` and the registers saved at an offset from the CFA
+    fn rules(&self, address: u64) -> Option<Rules<'_>> {
+        let mut ctx = UninitializedUnwindContext::new();
+        if let Some((data, bases)) = &self.eh_frame {
+            let eh_frame = gimli::EhFrame::new(data, Endian::default());
+            if let Ok(row) = eh_frame.unwind_info_for_address(
+                bases,
+                &mut ctx,
+                address,
+                gimli::EhFrame::cie_from_offset,
+            ) {
+                return Some((row.cfa().clone(), saved_registers(row)));
+            }
+        }
+        if let Some(data) = &self.debug_frame {
+            let debug_frame = gimli::DebugFrame::new(data, Endian::default());
+            let bases = gimli::BaseAddresses::default();
+            if let Ok(row) = debug_frame.unwind_info_for_address(
+                &bases,
+                &mut ctx,
+                address,
+                gimli::DebugFrame::cie_from_offset,
+            ) {
+                return Some((row.cfa().clone(), saved_registers(row)));
+            }
+        }
+        None
+    }
+
+    fn symbolize(&self, pc: u64, lookup: u64) -> StackFrame {
+        if !(self.text.0..self.text.1).contains(&lookup) {
+            return StackFrame {
+                pc,
+                function: None,
+                location: None,
+            };
+        }
+        let index = self
+            .symbols
+            .partition_point(|&(address, _)| address <= lookup);
+        let function = index
+            .checked_sub(1)
+            .map(|index| &self.symbols[index])
+            .map(|(address, name)| (name.clone(), pc - address));
+        StackFrame {
+            pc,
+            function,
+            location: self.lines.locate(lookup - self.base),
+        }
+    }
+}
+
+/// The registers saved on the stack, the others keep their value in the caller
+fn saved_registers<R: gimli::Reader>(
+    row: &gimli::UnwindTableRow<R>,
+) -> Vec<(gimli::Register, i64)> {
+    row.registers()
+        .filter_map(|(register, rule)| match rule {
+            RegisterRule::Offset(offset) => Some((*register, *offset)),
+            _ => None,
+        })
+        .collect()
+}
+
+impl fmt::Display for StackFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "0x{:x}", self.pc)?;
+        match &self.function {
+            Some((name, offset)) => write!(f, " {}+0x{:x}", name, offset)?,
+            None => write!(f, " ??")?,
+        }
+        if let Some(location) = &self.location {
+            write!(f, " at {}", location)?;
+        }
+        Ok(())
+    }
+}
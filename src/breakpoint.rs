@@ -1,4 +1,4 @@
-use crate::defs::{Result, ProcessInfo};
+use crate::defs::{ProcessInfo, Result};
 
 #[derive(Debug)]
 pub struct Breakpoint {
@@ -6,24 +6,46 @@ pub struct Breakpoint {
     address: u64,
     /// the old data that was at the bp address
     old_data: u8,
+    /// whether the int3 is written
+    enabled: bool,
 }
 
-
 impl<'a> Breakpoint {
     pub fn new(address: u64) -> Self {
-        Self { address, old_data: 0, }
+        Self {
+            address,
+            old_data: 0,
+            enabled: false,
+        }
+    }
+
+    /// A breakpoint whose int3 was already written over `old_data`
+    pub fn enabled(address: u64, old_data: u8) -> Self {
+        Self {
+            address,
+            old_data,
+            enabled: true,
+        }
     }
 
     pub fn enable<T: ProcessInfo>(&mut self, tracee: &'a mut T) -> Result<()> {
+        if self.enabled {
+            return Ok(());
+        }
         let mut mem: [u8; 1] = [0];
         tracee.read_at(self.address, &mut mem)?;
         self.old_data = mem[0];
         tracee.write_at(self.address, &[0xcc])?;
+        self.enabled = true;
         Ok(())
     }
 
     pub fn disable<T: ProcessInfo>(&mut self, tracee: &'a mut T) -> Result<()> {
+        if !self.enabled {
+            return Ok(());
+        }
         tracee.write_at(self.address, &[self.old_data])?;
+        self.enabled = false;
         Ok(())
     }
 
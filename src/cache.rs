@@ -0,0 +1,216 @@
+//! On-disk cache of the functions resolved from a binary
+//!
+//! Resolving the functions of a big binary means parsing all of its DWARF and disassembling
+//! every function, so the result is stored in `$XDG_CACHE_HOME/ftrace-rs` (or
+//! `~/.cache/ftrace-rs`) and reused as long as the binary did not change. The cache is only
+//! an optimization: entries which can't be read or written are warned about and skipped.
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use object::Object;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+
+use crate::defs::Result;
+use crate::function::Function;
+
+/// Bump this whenever the layout of [`Function`] or the analyses change
+const CACHE_VERSION: u32 = 1;
+
+/// Identifies a binary and the way its functions were resolved
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct CacheKey {
+    version: u32,
+    path: PathBuf,
+    build_id: Option<Vec<u8>>,
+    mtime: (u64, u32),
+    size: u64,
+    /// how the functions were resolved, e.g. the function source
+    options: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CacheEntry {
+    key: CacheKey,
+    funcs: Vec<Function>,
+}
+
+impl CacheKey {
+    pub fn new(binary: &Path, obj: &object::File, options: String) -> Result<Self> {
+        let path = binary.canonicalize()?;
+        let metadata = std::fs::metadata(&path)?;
+        let mtime = metadata
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        Ok(Self {
+            version: CACHE_VERSION,
+            path,
+            build_id: obj.build_id()?.map(|id| id.to_vec()),
+            mtime: (mtime.as_secs(), mtime.subsec_nanos()),
+            size: metadata.len(),
+            options,
+        })
+    }
+
+    fn cache_file(&self) -> Option<PathBuf> {
+        let mut hasher = DefaultHasher::new();
+        self.path.hash(&mut hasher);
+        self.options.hash(&mut hasher);
+        Some(cache_dir()?.join(format!("{:016x}.bin", hasher.finish())))
+    }
+}
+
+fn cache_dir() -> Option<PathBuf> {
+    let base = match std::env::var_os("XDG_CACHE_HOME") {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(std::env::var_os("HOME")?).join(".cache"),
+    };
+    Some(base.join("ftrace-rs"))
+}
+
+/// Returns the cached functions if they were stored with the same key
+pub fn load(key: &CacheKey) -> Option<Vec<Function>> {
+    read(&key.cache_file()?, key)
+}
+
+fn read(file: &Path, key: &CacheKey) -> Option<Vec<Function>> {
+    let data = match std::fs::read(file) {
+        Ok(data) => data,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return None,
+        Err(err) => {
+            warn!(?file, ?err, "could not read the cache");
+            return None;
+        }
+    };
+    match bincode::deserialize::<CacheEntry>(&data) {
+        Ok(entry) if entry.key == *key => {
+            debug!(?file, "using cached functions");
+            Some(entry.funcs)
+        }
+        Ok(_) => {
+            debug!(?file, "stale cache");
+            None
+        }
+        Err(err) => {
+            warn!(?file, ?err, "could not read the cache");
+            None
+        }
+    }
+}
+
+/// Stores the functions in the cache and hands them back, a failure is only warned about
+pub fn store(key: CacheKey, funcs: Vec<Function>) -> Vec<Function> {
+    let file = match key.cache_file() {
+        Some(file) => file,
+        None => return funcs,
+    };
+    let entry = CacheEntry { key, funcs };
+    match write(&file, &entry) {
+        Ok(()) => debug!(?file, "stored functions in the cache"),
+        Err(err) => warn!(?file, ?err, "could not store the functions in the cache"),
+    }
+    entry.funcs
+}
+
+/// Writes the entry to a temporary file which then replaces `file`, so that a tracer running
+/// at the same time never reads half of an entry
+fn write(file: &Path, entry: &CacheEntry) -> Result<()> {
+    if let Some(dir) = file.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let tmp = file.with_extension(format!("{}.tmp", std::process::id()));
+    let written =
+        std::fs::write(&tmp, bincode::serialize(entry)?).and_then(|()| std::fs::rename(&tmp, file));
+    if written.is_err() {
+        let _ = std::fs::remove_file(&tmp);
+    }
+    Ok(written?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key() -> CacheKey {
+        CacheKey {
+            version: CACHE_VERSION,
+            path: PathBuf::from("/bin/app"),
+            build_id: Some(vec![0xde, 0xad]),
+            mtime: (1_700_000_000, 5),
+            size: 4096,
+            options: "Heuristic inline=false".to_owned(),
+        }
+    }
+
+    fn function(address: u64, name: &str) -> Function {
+        Function {
+            address,
+            prologue_end_addr: Some(address + 4),
+            name: name.to_owned(),
+            parameters: vec![],
+            return_type: None,
+            inlined: false,
+            exit_addrs: vec![],
+            decl_file: Some("app.c".to_owned()),
+            decl_line: Some(3),
+            unit: None,
+        }
+    }
+
+    /// A cache file of its own for each test, the tests run in parallel
+    fn cache_file(test: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("ftrace-rs-cache-{}", std::process::id()));
+        dir.join(format!("{}.bin", test))
+    }
+
+    #[test]
+    fn stored_functions_read_back() {
+        let file = cache_file("round_trip");
+        let entry = CacheEntry {
+            key: key(),
+            funcs: vec![function(0x1000, "main"), function(0x1040, "fact")],
+        };
+        write(&file, &entry).unwrap();
+        let funcs = read(&file, &key()).unwrap();
+        let _ = std::fs::remove_file(&file);
+
+        assert_eq!(funcs.len(), 2);
+        assert_eq!(funcs[1].name, "fact");
+        assert_eq!(funcs[1].address, 0x1040);
+        assert_eq!(funcs[1].prologue_end_addr, Some(0x1044));
+        assert_eq!(funcs[1].decl_line, Some(3));
+        // the temporary file was renamed
+        let tmp = file.with_extension(format!("{}.tmp", std::process::id()));
+        assert!(!tmp.exists());
+    }
+
+    #[test]
+    fn changed_binaries_are_not_read_from_the_cache() {
+        let file = cache_file("stale");
+        let entry = CacheEntry {
+            key: key(),
+            funcs: vec![function(0x1000, "main")],
+        };
+        write(&file, &entry).unwrap();
+        let stale: [fn(&mut CacheKey); 5] = [
+            |key| key.mtime.1 += 1,
+            |key| key.size += 1,
+            |key| key.build_id = None,
+            |key| key.version += 1,
+            |key| key.options.push_str(" lazy"),
+        ];
+        for change in stale {
+            let mut key = key();
+            change(&mut key);
+            assert!(read(&file, &key).is_none(), "{:?}", key);
+        }
+        assert!(read(&file, &key()).is_some());
+        let _ = std::fs::remove_file(&file);
+
+        assert!(read(&file, &key()).is_none());
+    }
+}
@@ -1,8 +1,13 @@
 use std::str::FromStr;
 
+use crate::utils::parse_address;
+
+#[derive(Debug, Clone, Copy)]
 pub enum FuncSource {
     Dwarf,
     Heuristic,
+    /// Merge every source available in the binary
+    Auto,
 }
 
 impl FromStr for FuncSource {
@@ -12,7 +17,77 @@ impl FromStr for FuncSource {
         match s {
             "dwarf" => Ok(Self::Dwarf),
             "heuristic" => Ok(Self::Heuristic),
+            "auto" => Ok(Self::Auto),
             _ => Err("no such source"),
         }
     }
 }
+
+/// A `--watch` target, `<variable|0xaddress>[:len]`
+#[derive(Debug, Clone)]
+pub struct WatchSpec {
+    pub target: WatchTarget,
+    /// bytes to watch, defaults to the size of the variable
+    pub len: Option<u64>,
+}
+
+#[derive(Debug, Clone)]
+pub enum WatchTarget {
+    /// a global, or one of its fields with `global.field`
+    Variable(String),
+    /// an address in the object file, as shown by objdump and friends
+    Address(u64),
+}
+
+impl FromStr for WatchSpec {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let (target, len) = match s.split_once(':') {
+            Some((target, len)) => (target, Some(len.parse().map_err(|_| "invalid length")?)),
+            None => (s, None),
+        };
+        let target = if target.starts_with("0x") {
+            WatchTarget::Address(parse_address(target).map_err(|_| "invalid address")?)
+        } else {
+            WatchTarget::Variable(target.to_owned())
+        };
+        Ok(Self { target, len })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(s: &str) -> std::result::Result<WatchSpec, &'static str> {
+        s.parse()
+    }
+
+    #[test]
+    fn parses_watch_targets() {
+        let spec = parse("counter").unwrap();
+        assert!(matches!(&spec.target, WatchTarget::Variable(name) if name == "counter"));
+        assert_eq!(spec.len, None);
+
+        let spec = parse("config.retries:4").unwrap();
+        assert!(matches!(&spec.target, WatchTarget::Variable(name) if name == "config.retries"));
+        assert_eq!(spec.len, Some(4));
+
+        let spec = parse("0x4010:16").unwrap();
+        assert!(matches!(spec.target, WatchTarget::Address(0x4010)));
+        assert_eq!(spec.len, Some(16));
+    }
+
+    #[test]
+    fn rejects_invalid_watch_targets() {
+        for (spec, error) in [
+            ("counter:", "invalid length"),
+            ("counter:four", "invalid length"),
+            ("0x4010:-1", "invalid length"),
+            ("0xzz", "invalid address"),
+        ] {
+            assert_eq!(parse(spec).unwrap_err(), error, "{}", spec);
+        }
+    }
+}
@@ -0,0 +1,549 @@
+//! Conditions on the arguments of a call, e.g. `fact:x>3` or `load_config:path=~"/etc"`
+//!
+//! A condition is a function name and an expression made of comparisons joined with `&&`,
+//! `||` and parentheses. The left side of a comparison is a parameter name, `argN` for the
+//! Nth parameter (from 0) or a register. Integers compare as signed, strings and `=~` regexes
+//! compare against the NUL terminated string the value points to, a string which can't be read
+//! (e.g. a NULL pointer) doesn't match.
+
+use std::cell::Cell;
+use std::str::FromStr;
+
+use regex::Regex;
+use tracing::warn;
+
+use crate::defs::{ProcessInfo, Registers, Result};
+use crate::function::{BaseTypeEncoding, FormalParameter, Function, TypeKind};
+use crate::process_ext::ProcessExt;
+
+/// Longest string read for a string comparison
+const MAX_STRING_LEN: usize = 4096;
+
+#[derive(Debug)]
+pub struct Condition {
+    /// name of the function the condition applies to
+    pub function: String,
+    expr: Expr,
+    /// whether a string which couldn't be read was warned about, only the first one is
+    warned: Cell<bool>,
+}
+
+#[derive(Debug)]
+enum Expr {
+    Or(Box<Expr>, Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Cmp(Operand, Op, Literal),
+}
+
+#[derive(Debug)]
+enum Operand {
+    /// a parameter name, or a register if no parameter has it
+    Name(String),
+    Arg(usize),
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Match,
+}
+
+#[derive(Debug)]
+enum Literal {
+    Int(i64),
+    Float(f64),
+    Str(String),
+    Regex(Regex),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    Int(i64),
+    Float(f64),
+    Str(String),
+    Op(&'static str),
+}
+
+impl Condition {
+    /// Evaluates the condition at the entry of `func`, `cfa` is the canonical frame address of
+    /// the call if known
+    pub fn eval<P: ProcessInfo>(
+        &self,
+        process: &P,
+        func: &Function,
+        cfa: Option<u64>,
+    ) -> Result<bool> {
+        let registers = process.get_registers()?;
+        self.expr.eval(process, &registers, cfa, func, &self.warned)
+    }
+}
+
+impl Expr {
+    fn eval<P: ProcessInfo>(
+        &self,
+        process: &P,
+        registers: &Registers,
+        cfa: Option<u64>,
+        func: &Function,
+        warned: &Cell<bool>,
+    ) -> Result<bool> {
+        match self {
+            Expr::Or(lhs, rhs) => Ok(lhs.eval(process, registers, cfa, func, warned)?
+                || rhs.eval(process, registers, cfa, func, warned)?),
+            Expr::And(lhs, rhs) => Ok(lhs.eval(process, registers, cfa, func, warned)?
+                && rhs.eval(process, registers, cfa, func, warned)?),
+            Expr::Cmp(operand, op, literal) => {
+                let (value, ty) = match operand.value(process, registers, cfa, func)? {
+                    Some(value) => value,
+                    None => {
+                        warn!(?operand, function = %func.name, "no such parameter or register");
+                        return Ok(false);
+                    }
+                };
+                let string = || match process.read_c_string(value, MAX_STRING_LEN) {
+                    Ok(string) => Some(string),
+                    Err(err) => {
+                        if !warned.replace(true) {
+                            warn!(%err, address = value, function = %func.name,
+                                "can't read the string of a condition, taking it as not matching");
+                        }
+                        None
+                    }
+                };
+                Ok(compare(value, ty, *op, literal, string))
+            }
+        }
+    }
+}
+
+impl Operand {
+    /// Returns the raw value of the operand and its type, if known
+    fn value<'f, P: ProcessInfo>(
+        &self,
+        process: &P,
+        registers: &Registers,
+        cfa: Option<u64>,
+        func: &'f Function,
+    ) -> Result<Option<(u64, Option<&'f TypeKind>)>> {
+        let param: Option<&FormalParameter> = match self {
+            Operand::Arg(n) => func
+                .parameters
+                .get(*n)
+                .and_then(|param| param.as_ref().ok()),
+            Operand::Name(name) => func
+                .parameters
+                .iter()
+                .flatten()
+                .find(|param| param.name.as_deref() == Some(name.as_str())),
+        };
+        if let Some(param) = param {
+            let value = process.get_param_value(registers, param, cfa)?;
+            return Ok(Some((value, param.ty.as_ref())));
+        }
+        match self {
+            Operand::Name(name) => match gimli::X86_64::name_to_register(name) {
+                Some(register) => Ok(Some((
+                    process.get_register_value(registers, register)?,
+                    None,
+                ))),
+                None => Ok(None),
+            },
+            Operand::Arg(_) => Ok(None),
+        }
+    }
+}
+
+/// Compares `value` with `literal`, `string` reads the string `value` points to
+fn compare(
+    value: u64,
+    ty: Option<&TypeKind>,
+    op: Op,
+    literal: &Literal,
+    string: impl FnOnce() -> Option<String>,
+) -> bool {
+    let ordering = match literal {
+        Literal::Int(expected) => signed(value, ty).cmp(expected),
+        Literal::Float(expected) => match float(value, ty).partial_cmp(expected) {
+            Some(ordering) => ordering,
+            // NaN compares unequal to everything
+            None => return matches!(op, Op::Ne),
+        },
+        Literal::Str(expected) => match string() {
+            Some(string) => string.cmp(expected),
+            None => return false,
+        },
+        Literal::Regex(regex) => return matches!(string(), Some(string) if regex.is_match(&string)),
+    };
+    use std::cmp::Ordering::*;
+    match op {
+        Op::Eq => ordering == Equal,
+        Op::Ne => ordering != Equal,
+        Op::Lt => ordering == Less,
+        Op::Le => ordering != Greater,
+        Op::Gt => ordering == Greater,
+        Op::Ge => ordering != Less,
+        // the parser only allows =~ with a regex
+        Op::Match => false,
+    }
+}
+
+/// Sign extends a value narrower than a register
+fn signed(value: u64, ty: Option<&TypeKind>) -> i64 {
+    match ty {
+        Some(TypeKind::BaseType(ty)) if ty.size > 0 && ty.size < 8 => {
+            let shift = 64 - ty.size * 8;
+            ((value << shift) as i64) >> shift
+        }
+        _ => value as i64,
+    }
+}
+
+fn float(value: u64, ty: Option<&TypeKind>) -> f64 {
+    match ty {
+        Some(TypeKind::BaseType(ty)) if matches!(ty.encoding, BaseTypeEncoding::Float) => {
+            match ty.size {
+                4 => f32::from_bits(value as u32) as f64,
+                _ => f64::from_bits(value),
+            }
+        }
+        _ => signed(value, ty) as f64,
+    }
+}
+
+impl FromStr for Condition {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let (function, expr) = s
+            .split_once(':')
+            .ok_or("expected <function>:<expression>")?;
+        let mut parser = Parser {
+            tokens: tokenize(expr)?,
+            pos: 0,
+        };
+        let expr = parser.or()?;
+        if let Some(token) = parser.peek() {
+            return Err(format!("unexpected {:?}", token));
+        }
+        Ok(Self {
+            function: function.to_owned(),
+            expr,
+            warned: Cell::new(false),
+        })
+    }
+}
+
+fn tokenize(s: &str) -> std::result::Result<Vec<Token>, String> {
+    const OPS: [&str; 13] = [
+        "==", "!=", "<=", ">=", "=~", "&&", "||", "<", ">", "=", "(", ")", "-",
+    ];
+    let mut tokens = vec![];
+    let mut rest = s.trim_start();
+    while let Some(c) = rest.chars().next() {
+        if c.is_ascii_alphabetic() || c == '_' {
+            let end = rest
+                .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+                .unwrap_or(rest.len());
+            tokens.push(Token::Ident(rest[..end].to_owned()));
+            rest = &rest[end..];
+        } else if c.is_ascii_digit() {
+            let end = rest
+                .find(|c: char| !c.is_ascii_alphanumeric() && c != '.')
+                .unwrap_or(rest.len());
+            tokens.push(number(&rest[..end])?);
+            rest = &rest[end..];
+        } else if c == '"' {
+            let mut string = String::new();
+            let mut chars = rest[1..].char_indices();
+            let end = loop {
+                match chars.next() {
+                    Some((i, '"')) => break i + 2,
+                    Some((_, '\\')) => match chars.next() {
+                        Some((_, c)) => string.push(c),
+                        None => return Err("unterminated string".to_owned()),
+                    },
+                    Some((_, c)) => string.push(c),
+                    None => return Err("unterminated string".to_owned()),
+                }
+            };
+            tokens.push(Token::Str(string));
+            rest = &rest[end..];
+        } else {
+            let op = OPS
+                .iter()
+                .find(|op| rest.starts_with(*op))
+                .ok_or_else(|| format!("unexpected character {:?}", c))?;
+            tokens.push(Token::Op(op));
+            rest = &rest[op.len()..];
+        }
+        rest = rest.trim_start();
+    }
+    Ok(tokens)
+}
+
+fn number(s: &str) -> std::result::Result<Token, String> {
+    let invalid = |_| format!("invalid number {:?}", s);
+    if let Some(hex) = s.strip_prefix("0x") {
+        i64::from_str_radix(hex, 16)
+            .map(Token::Int)
+            .map_err(invalid)
+    } else if s.contains('.') {
+        s.parse()
+            .map(Token::Float)
+            .map_err(|_| format!("invalid number {:?}", s))
+    } else {
+        s.parse()
+            .map(Token::Int)
+            .map_err(|_| format!("invalid number {:?}", s))
+    }
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> std::result::Result<Token, String> {
+        let token = self.peek().cloned().ok_or("unexpected end of condition")?;
+        self.pos += 1;
+        Ok(token)
+    }
+
+    fn eat(&mut self, op: &str) -> bool {
+        if matches!(self.peek(), Some(Token::Op(o)) if *o == op) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn or(&mut self) -> std::result::Result<Expr, String> {
+        let mut expr = self.and()?;
+        while self.eat("||") {
+            expr = Expr::Or(Box::new(expr), Box::new(self.and()?));
+        }
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> std::result::Result<Expr, String> {
+        let mut expr = self.comparison()?;
+        while self.eat("&&") {
+            expr = Expr::And(Box::new(expr), Box::new(self.comparison()?));
+        }
+        Ok(expr)
+    }
+
+    fn comparison(&mut self) -> std::result::Result<Expr, String> {
+        if self.eat("(") {
+            let expr = self.or()?;
+            if !self.eat(")") {
+                return Err("expected )".to_owned());
+            }
+            return Ok(expr);
+        }
+        let operand = match self.next()? {
+            Token::Ident(name) => match name.strip_prefix("arg").map(str::parse) {
+                Some(Ok(n)) => Operand::Arg(n),
+                _ => Operand::Name(name),
+            },
+            token => return Err(format!("expected a parameter, got {:?}", token)),
+        };
+        let op = match self.next()? {
+            Token::Op("==") | Token::Op("=") => Op::Eq,
+            Token::Op("!=") => Op::Ne,
+            Token::Op("<") => Op::Lt,
+            Token::Op("<=") => Op::Le,
+            Token::Op(">") => Op::Gt,
+            Token::Op(">=") => Op::Ge,
+            Token::Op("=~") => Op::Match,
+            token => return Err(format!("expected a comparison, got {:?}", token)),
+        };
+        let negative = self.eat("-");
+        let literal = match (self.next()?, op) {
+            (Token::Str(regex), Op::Match) => {
+                Literal::Regex(Regex::new(&regex).map_err(|err| err.to_string())?)
+            }
+            (_, Op::Match) => return Err("=~ needs a string".to_owned()),
+            (Token::Int(n), _) => Literal::Int(if negative { -n } else { n }),
+            (Token::Float(n), _) => Literal::Float(if negative { -n } else { n }),
+            (Token::Str(s), Op::Eq | Op::Ne) if !negative => Literal::Str(s),
+            (token, _) => return Err(format!("unexpected {:?}", token)),
+        };
+        Ok(Expr::Cmp(operand, op, literal))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::function::BaseType;
+
+    fn parse(s: &str) -> std::result::Result<Condition, String> {
+        s.parse()
+    }
+
+    fn int(size: u64) -> TypeKind {
+        TypeKind::BaseType(BaseType {
+            size,
+            encoding: BaseTypeEncoding::Unsigned,
+        })
+    }
+
+    fn cmp(op: &str, literal: &str) -> (Op, Literal) {
+        match parse(&format!("f:x {} {}", op, literal)).unwrap().expr {
+            Expr::Cmp(_, op, literal) => (op, literal),
+            expr => panic!("not a comparison: {:?}", expr),
+        }
+    }
+
+    #[test]
+    fn parses_expressions() {
+        let condition = parse("fact:n>3 && (arg1 == -2 || rdi != 0x10)").unwrap();
+        assert_eq!(condition.function, "fact");
+        let (lhs, rhs) = match &condition.expr {
+            Expr::And(lhs, rhs) => (lhs, rhs),
+            expr => panic!("not an and: {:?}", expr),
+        };
+        match &**lhs {
+            Expr::Cmp(Operand::Name(name), Op::Gt, Literal::Int(3)) => assert_eq!(name, "n"),
+            expr => panic!("unexpected {:?}", expr),
+        }
+        match &**rhs {
+            Expr::Or(lhs, rhs) => {
+                assert!(matches!(
+                    &**lhs,
+                    Expr::Cmp(Operand::Arg(1), Op::Eq, Literal::Int(-2))
+                ));
+                match &**rhs {
+                    Expr::Cmp(Operand::Name(name), Op::Ne, Literal::Int(16)) => {
+                        assert_eq!(name, "rdi")
+                    }
+                    expr => panic!("unexpected {:?}", expr),
+                }
+            }
+            expr => panic!("not an or: {:?}", expr),
+        }
+    }
+
+    #[test]
+    fn and_binds_tighter_than_or() {
+        let condition = parse("f:a==1 || b==2 && c==3").unwrap();
+        assert!(matches!(&condition.expr, Expr::Or(_, rhs) if matches!(**rhs, Expr::And(..))));
+    }
+
+    #[test]
+    fn parses_literals() {
+        assert!(matches!(cmp("=", "1.5"), (Op::Eq, Literal::Float(n)) if n == 1.5));
+        assert!(matches!(cmp("<=", "-0.5"), (Op::Le, Literal::Float(n)) if n == -0.5));
+        assert!(matches!(cmp("==", r#""a \"b\"""#), (Op::Eq, Literal::Str(s)) if s == r#"a "b""#));
+        let regex = cmp("=~", r#""^/etc""#);
+        assert!(matches!(regex, (Op::Match, Literal::Regex(r)) if r.as_str() == "^/etc"));
+    }
+
+    #[test]
+    fn rejects_invalid_conditions() {
+        for (condition, error) in [
+            ("fact", "expected <function>:<expression>"),
+            ("f:x >", "unexpected end of condition"),
+            ("f:x", "unexpected end of condition"),
+            ("f:(x > 1", "expected )"),
+            ("f:x > 1 y", "unexpected Ident(\"y\")"),
+            ("f:x > 0xzz", "invalid number \"0xzz\""),
+            ("f:x > 1.2.3", "invalid number \"1.2.3\""),
+            ("f:x == \"abc", "unterminated string"),
+            ("f:x =~ 3", "=~ needs a string"),
+            ("f:x < \"abc\"", "unexpected Str(\"abc\")"),
+            ("f:x == -\"abc\"", "unexpected Str(\"abc\")"),
+            ("f:3 == x", "expected a parameter, got Int(3)"),
+            ("f:x ! 3", "unexpected character '!'"),
+            ("f:x && y", "expected a comparison, got Op(\"&&\")"),
+        ] {
+            assert_eq!(parse(condition).unwrap_err(), error, "{}", condition);
+        }
+        assert!(parse("f:x =~ \"(\"").is_err());
+    }
+
+    #[test]
+    fn compares_integers_with_their_width() {
+        let (op, literal) = cmp("==", "-1");
+        assert!(compare(0xffff_ffff, Some(&int(4)), op, &literal, || None));
+        assert!(!compare(0xffff_ffff, None, op, &literal, || None));
+        assert!(compare(u64::MAX, None, op, &literal, || None));
+        let (op, literal) = cmp("<", "0");
+        assert!(compare(0x80, Some(&int(1)), op, &literal, || None));
+        assert!(!compare(0x80, Some(&int(2)), op, &literal, || None));
+    }
+
+    #[test]
+    fn compares_floats() {
+        let double = TypeKind::BaseType(BaseType {
+            size: 8,
+            encoding: BaseTypeEncoding::Float,
+        });
+        let (op, literal) = cmp(">", "1.0");
+        assert!(compare(
+            1.5f64.to_bits(),
+            Some(&double),
+            op,
+            &literal,
+            || None
+        ));
+        assert!(!compare(
+            0.5f64.to_bits(),
+            Some(&double),
+            op,
+            &literal,
+            || None
+        ));
+        // NaN is only unequal
+        assert!(!compare(
+            f64::NAN.to_bits(),
+            Some(&double),
+            op,
+            &literal,
+            || None
+        ));
+        let (op, literal) = cmp("!=", "1.0");
+        assert!(compare(
+            f64::NAN.to_bits(),
+            Some(&double),
+            op,
+            &literal,
+            || None
+        ));
+    }
+
+    #[test]
+    fn compares_strings() {
+        let string = |s: &str| {
+            let s = s.to_owned();
+            move || Some(s)
+        };
+        let (op, literal) = cmp("==", r#""/etc/passwd""#);
+        assert!(compare(0x1000, None, op, &literal, string("/etc/passwd")));
+        assert!(!compare(0x1000, None, op, &literal, string("/etc/hosts")));
+        let (op, literal) = cmp("=~", r#""^/etc/""#);
+        assert!(compare(0x1000, None, op, &literal, string("/etc/hosts")));
+        assert!(!compare(0x1000, None, op, &literal, string("/home")));
+    }
+
+    #[test]
+    fn unreadable_strings_never_match() {
+        for (op, literal) in [("==", r#""x""#), ("!=", r#""x""#), ("=~", r#"".*""#)] {
+            let (op, literal) = cmp(op, literal);
+            assert!(!compare(0, None, op, &literal, || None));
+        }
+    }
+}
@@ -0,0 +1,211 @@
+//! x86_64 debug registers, for breakpoints which don't modify the code
+//!
+//! DR0–DR3 hold the addresses, DR6 tells which of them triggered and DR7 enables them and
+//! sets what they trigger on.
+//! Refs:
+//! - Intel SDM Vol. 3B, 17.2 Debug Registers
+
+use nix::libc;
+use nix::unistd::Pid;
+
+use crate::defs::Result;
+
+/// Number of address registers, and so of hardware breakpoints
+pub const SLOTS: usize = 4;
+
+const DR6: usize = 6;
+const DR7: usize = 7;
+
+/// Resume flag, suppresses the instruction breakpoint of the next instruction
+pub const EFLAGS_RF: u64 = 1 << 16;
+
+/// The accesses which trigger a hardware breakpoint
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Trigger {
+    Execute,
+    Write,
+    /// reads or writes
+    Access,
+}
+
+#[derive(Debug)]
+pub struct HardwareBreakpoint {
+    /// address of the breakpoint
+    pub address: u64,
+    /// number of bytes watched, 1 for execution
+    pub len: u64,
+    pub trigger: Trigger,
+    /// the debug register holding the address
+    slot: usize,
+}
+
+impl HardwareBreakpoint {
+    pub fn new(address: u64, slot: usize) -> Self {
+        Self {
+            address,
+            len: 1,
+            trigger: Trigger::Execute,
+            slot,
+        }
+    }
+
+    /// A watchpoint on `len` bytes at `address`, which has to be one of [`aligned_chunks`]
+    pub fn watchpoint(address: u64, len: u64, trigger: Trigger, slot: usize) -> Self {
+        Self {
+            address,
+            len,
+            trigger,
+            slot,
+        }
+    }
+
+    pub fn enable(&self, pid: Pid) -> Result<()> {
+        write(pid, self.slot, self.address)?;
+        let rw = match self.trigger {
+            Trigger::Execute => 0b00,
+            Trigger::Write => 0b01,
+            Trigger::Access => 0b11,
+        };
+        let len = match self.len {
+            2 => 0b01,
+            8 => 0b10,
+            4 => 0b11,
+            _ => 0b00,
+        };
+        let control = (len << 2 | rw) << (16 + self.slot * 4);
+        let dr7 = read(pid, DR7)? & !slot_bits(self.slot) | local_enable(self.slot) | control;
+        write(pid, DR7, dr7)
+    }
+
+    pub fn disable(&self, pid: Pid) -> Result<()> {
+        let dr7 = read(pid, DR7)? & !slot_bits(self.slot);
+        write(pid, DR7, dr7)
+    }
+}
+
+/// Splits `len` bytes at `address` into the naturally aligned 1, 2, 4 or 8 byte ranges a
+/// debug register can watch
+pub fn aligned_chunks(address: u64, len: u64) -> Vec<(u64, u64)> {
+    let mut chunks = vec![];
+    let (mut address, end) = (address, address + len);
+    while address < end {
+        let size = [8, 4, 2, 1]
+            .into_iter()
+            .find(|size| address % size == 0 && address + size <= end)
+            .unwrap_or(1);
+        chunks.push((address, size));
+        address += size;
+    }
+    chunks
+}
+
+/// Returns the slots which caused the last debug exception, and clears them
+pub fn take_triggered(pid: Pid) -> Result<Vec<usize>> {
+    let dr6 = read(pid, DR6)?;
+    let triggered: Vec<_> = (0..SLOTS).filter(|slot| dr6 & (1 << slot) != 0).collect();
+    if !triggered.is_empty() {
+        // the processor never clears DR6 by itself
+        write(pid, DR6, 0)?;
+    }
+    Ok(triggered)
+}
+
+const fn local_enable(slot: usize) -> u64 {
+    1 << (slot * 2)
+}
+
+/// The enable, R/W and LEN bits of a slot in DR7
+const fn slot_bits(slot: usize) -> u64 {
+    0b11 << (slot * 2) | 0b1111 << (16 + slot * 4)
+}
+
+fn offset(register: usize) -> usize {
+    std::mem::offset_of!(libc::user, u_debugreg) + register * std::mem::size_of::<u64>()
+}
+
+fn read(pid: Pid, register: usize) -> Result<u64> {
+    // nix has no wrapper for PTRACE_PEEKUSER, its result is only an error if errno is set
+    nix::errno::Errno::clear();
+    let res = unsafe {
+        libc::ptrace(
+            libc::PTRACE_PEEKUSER,
+            pid.as_raw(),
+            offset(register) as *mut libc::c_void,
+            std::ptr::null_mut::<libc::c_void>(),
+        )
+    };
+    if res == -1 && nix::errno::Errno::last() != nix::errno::Errno::UnknownErrno {
+        return Err(nix::errno::Errno::last().into());
+    }
+    Ok(res as u64)
+}
+
+fn write(pid: Pid, register: usize, value: u64) -> Result<()> {
+    let res = unsafe {
+        libc::ptrace(
+            libc::PTRACE_POKEUSER,
+            pid.as_raw(),
+            offset(register) as *mut libc::c_void,
+            value as *mut libc::c_void,
+        )
+    };
+    nix::errno::Errno::result(res)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn aligned_ranges_take_one_register() {
+        for len in [1, 2, 4, 8] {
+            assert_eq!(aligned_chunks(0x1000, len), [(0x1000, len)]);
+        }
+        assert_eq!(aligned_chunks(0x1004, 4), [(0x1004, 4)]);
+        assert_eq!(aligned_chunks(0x1006, 2), [(0x1006, 2)]);
+        assert!(aligned_chunks(0x1000, 0).is_empty());
+    }
+
+    #[test]
+    fn unaligned_ranges_are_split() {
+        assert_eq!(aligned_chunks(0x1001, 2), [(0x1001, 1), (0x1002, 1)]);
+        assert_eq!(aligned_chunks(0x1002, 4), [(0x1002, 2), (0x1004, 2)]);
+        assert_eq!(
+            aligned_chunks(0x1003, 8),
+            [(0x1003, 1), (0x1004, 4), (0x1008, 2), (0x100a, 1)]
+        );
+        // an aligned start with an odd length
+        assert_eq!(
+            aligned_chunks(0x1000, 7),
+            [(0x1000, 4), (0x1004, 2), (0x1006, 1)]
+        );
+    }
+
+    #[test]
+    fn long_ranges_need_more_registers_than_there_are() {
+        let chunks = aligned_chunks(0x1000, 40);
+        assert_eq!(chunks.len(), 5);
+        assert!(chunks.len() > SLOTS);
+        assert!(chunks.iter().all(|&(_, len)| len == 8));
+        let chunks = aligned_chunks(0x1001, 16);
+        assert_eq!(
+            chunks,
+            [
+                (0x1001, 1),
+                (0x1002, 2),
+                (0x1004, 4),
+                (0x1008, 8),
+                (0x1010, 1)
+            ]
+        );
+        // the chunks cover the range exactly
+        for (address, len) in [(0x1001, 16), (0x1003, 29), (0x1000, 40)] {
+            let chunks = aligned_chunks(address, len);
+            assert_eq!(chunks[0].0, address);
+            assert!(chunks.windows(2).all(|w| w[0].0 + w[0].1 == w[1].0));
+            assert_eq!(chunks.iter().map(|&(_, len)| len).sum::<u64>(), len);
+            assert!(chunks.iter().all(|&(address, len)| address % len == 0));
+        }
+    }
+}
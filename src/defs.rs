@@ -5,9 +5,11 @@ use std::process::Command;
 use crate::error;
 
 pub use gimli::Register;
+pub use nix::sys::signal::Signal;
 
 // TODO: this and Register both is pretty confusing namingwise
 pub type Registers = nix::libc::user_regs_struct;
+pub type FpRegisters = nix::libc::user_fpregs_struct;
 
 pub type Result<T> = std::result::Result<T, error::Error>;
 
@@ -33,21 +35,41 @@ pub trait ProcessInfo {
     // TODO: should this be DebuggerEngine?
     fn set_registers(&self, regs: Registers) -> Result<()>;
 
+    /// Gets the floating point and vector registers of the process
+    fn get_fp_registers(&self) -> Result<FpRegisters>;
+
     /// Read memory at address
     fn read_at(&self, address: u64, data: &mut [u8]) -> io::Result<usize>;
 
+    /// Read memory at every `(address, buffer)`, fails unless all of them are read whole
+    fn read_many(&self, reads: &mut [(u64, &mut [u8])]) -> io::Result<()> {
+        for (address, data) in reads.iter_mut() {
+            if self.read_at(*address, data)? != data.len() {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+        }
+        Ok(())
+    }
+
     /// Write memory at address
     fn write_at(&mut self, address: u64, data: &[u8]) -> io::Result<usize>;
+
+    /// Another handle on the same process, which doesn't borrow this one
+    fn boxed(&self) -> Box<dyn ProcessInfo>;
 }
 
 #[derive(Debug)]
 pub enum DebuggerStatus<P: ProcessInfo> {
     /// Breakpoint hit for the Pid at address u64
     BreakpointHit(P, u64),
+    /// The watchpoint covering address u64 was triggered, the access already happened
+    WatchpointHit(P, u64),
     /// Stopeed for some reason
     // TODO: add reason
     Stopped(P),
-    /// Exited(Pid, exit_code)
+    /// Stopped by a signal, which is only delivered with [`DebuggerEngine::cont_with_signal`]
+    Signaled(P, Signal),
+    /// Exited(Pid, exit_code), a process killed by a signal exits with 128 + the signal
     Exited(P, i32),
     /// Some unknown status
     Unknown,
@@ -60,7 +82,35 @@ pub trait DebuggerEngine {
     where
         Self: Sized;
     fn set_breakpoint(&mut self, pid: &mut Self::Process, address: u64) -> Result<()>;
+    /// Sets a breakpoint at every address, engines can batch the memory accesses
+    fn set_breakpoints(&mut self, pid: &mut Self::Process, addresses: &[u64]) -> Result<()> {
+        for &address in addresses {
+            self.set_breakpoint(pid, address)?;
+        }
+        Ok(())
+    }
+    /// Sets a breakpoint which doesn't modify the code, engines which are out of them or
+    /// don't have any set a normal breakpoint instead
+    fn set_hw_breakpoint(&mut self, pid: &mut Self::Process, address: u64) -> Result<()> {
+        self.set_breakpoint(pid, address)
+    }
+    /// Removes the breakpoint at `address`, whichever kind it is
+    fn remove_breakpoint(&mut self, pid: &mut Self::Process, address: u64) -> Result<()>;
+    /// Watches `len` bytes at `address` for writes, or any access if `reads` is set
+    fn set_watchpoint(
+        &mut self,
+        pid: &mut Self::Process,
+        address: u64,
+        len: u64,
+        reads: bool,
+    ) -> Result<()>;
     fn cont(&mut self, pid: &mut Self::Process) -> Result<()>;
+    /// Continues and delivers the signal the process was stopped by
+    fn cont_with_signal(&mut self, pid: &mut Self::Process, signal: Signal) -> Result<()>;
+    /// Removes every breakpoint and lets the process run on its own
+    fn detach(&mut self, pid: &mut Self::Process) -> Result<()>;
+    /// Kills the process, its exit is still reported by `wait`
+    fn kill(&mut self, pid: &mut Self::Process) -> Result<()>;
     // fn step(&mut self, pid: Pid) -> Result<()>;
     fn wait(&mut self) -> Result<DebuggerStatus<Self::Process>>
     where
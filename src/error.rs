@@ -14,9 +14,54 @@ pub enum Error {
 
     #[error("ddbug DWARF error")]
     Ddbug(#[from] ddbug_parser::Error),
+
+    #[error("analysis cache error")]
+    Cache(#[from] bincode::Error),
+
+    #[error("unknown register {0}")]
+    UnknownRegister(u16),
+
+    #[error("no variable named {0}")]
+    UnknownVariable(String),
+
+    #[error("the object filters don't match {0}, the only object traced")]
+    ObjectFilter(String),
+
+    #[error("no traced function named {0}, it must be defined in the binary and pass the filters")]
+    UntracedFunction(String),
+
+    #[error("script error: {0}")]
+    Script(String),
+
+    #[error("unknown script action {0:?}, expected continue, skip, detach or kill")]
+    UnknownAction(String),
+
+    #[error("{0} is not mapped in the traced process")]
+    NotMapped(String),
+
+    #[error("the path {0:?} is not valid UTF-8, which reading debug info requires")]
+    NonUtf8Path(std::path::PathBuf),
+
+    #[error("{0} is imported from a shared library, whose functions are never traced")]
+    ImportedFunction(String),
+
+    #[error(
+        "errno can't be injected, the binary has no errno of its own (is it dynamically linked?)"
+    )]
+    NoErrno,
+
+    #[error("not enough free debug registers to watch {0} bytes")]
+    NoFreeDebugRegister(u64),
+}
+
+impl From<Box<rhai::EvalAltResult>> for Error {
+    fn from(err: Box<rhai::EvalAltResult>) -> Self {
+        // rhai errors can't be sent across threads
+        Self::Script(err.to_string())
+    }
 }
 
-#[derive(Debug)]
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
 pub enum ParamFindingFailure {
     DwarfNoSize,
     DwarfNoFrameLocNoReg,
@@ -0,0 +1,302 @@
+//! Which functions get traced
+//!
+//! Every kind of filter which is given has to match, within a kind any of the given patterns
+//! can match. `--only`/`--ignore` and the runtime deny list work on the demangled names.
+
+use std::str::FromStr;
+
+use regex::Regex;
+
+use crate::function::Function;
+use crate::utils::parse_address;
+
+/// Startup and shutdown code of the C runtime, libstdc++ and Rust std, which is in every
+/// trace but rarely of interest
+const RUNTIME_FUNCTIONS: &[&str] = &[
+    // crt1.o, crti.o and crtbegin.o
+    "_start",
+    "_init",
+    "_fini",
+    "_dl_relocate_static_pie",
+    "__libc_csu_init",
+    "__libc_csu_fini",
+    "__libc_start_main",
+    "__libc_start_call_main",
+    "frame_dummy",
+    "register_tm_clones",
+    "deregister_tm_clones",
+    "__do_global_dtors_aux",
+    "__cxa_finalize",
+    "__gmon_start__",
+    // static constructors emitted for C++ globals
+    "_GLOBAL__sub_I_.*",
+    "__static_initialization_and_destruction_0.*",
+    // the Rust main shim and what it calls around the user's main
+    "std::rt::lang_start.*",
+    "std::rt::init.*",
+    "std::rt::cleanup.*",
+];
+
+/// Matches the names of [`RUNTIME_FUNCTIONS`]
+pub fn runtime_functions() -> Regex {
+    Regex::new(&format!("^({})$", RUNTIME_FUNCTIONS.join("|")))
+        .expect("the runtime function patterns are valid")
+}
+
+/// A shell like pattern on paths, `*` and `?` don't match `/`, `**` matches anything
+///
+/// Relative patterns can match the end of a path, so `net/*.c` matches `/src/net/tcp.c`.
+#[derive(Debug, Clone)]
+pub struct Glob(Regex);
+
+impl Glob {
+    pub fn is_match(&self, path: &str) -> bool {
+        self.0.is_match(path)
+    }
+}
+
+impl FromStr for Glob {
+    type Err = regex::Error;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let mut regex = String::from(if s.starts_with('/') { "^" } else { "(^|/)" });
+        let mut chars = s.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '*' if chars.peek() == Some(&'*') => {
+                    chars.next();
+                    regex.push_str(".*");
+                }
+                '*' => regex.push_str("[^/]*"),
+                '?' => regex.push_str("[^/]"),
+                c => regex.push_str(&regex::escape(&c.to_string())),
+            }
+        }
+        regex.push('$');
+        Regex::new(&regex).map(Self)
+    }
+}
+
+/// Addresses from `start` up to `end`, given as `0xstart-0xend` or `0xstart+0xlen`
+#[derive(Debug, Clone, Copy)]
+pub struct AddressRange {
+    start: u64,
+    end: u64,
+}
+
+impl AddressRange {
+    pub fn contains(&self, address: u64) -> bool {
+        address >= self.start && address < self.end
+    }
+}
+
+impl FromStr for AddressRange {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let parse = |s: &str| parse_address(s.trim()).map_err(|_| "invalid address");
+        if let Some((start, end)) = s.split_once('-') {
+            Ok(Self {
+                start: parse(start)?,
+                end: parse(end)?,
+            })
+        } else if let Some((start, len)) = s.split_once('+') {
+            let start = parse(start)?;
+            Ok(Self {
+                start,
+                end: start + parse(len)?,
+            })
+        } else {
+            Err("expected <start>-<end> or <start>+<len>")
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct FunctionFilter {
+    pub only: Option<Regex>,
+    pub ignore: Option<Regex>,
+    /// the built-in deny list, see [`runtime_functions`]
+    pub runtime: Option<Regex>,
+    /// globs on the file the function is declared in
+    pub files: Vec<Glob>,
+    /// globs on the name of the compile unit
+    pub units: Vec<Glob>,
+    /// names of the crates of Rust functions
+    pub crates: Vec<String>,
+    /// globs on the path of the object file containing the function, only the traced binary
+    /// for now: the tracer fails if none match it
+    pub objects: Vec<Glob>,
+    /// ranges of addresses in the object file, as shown by objdump and friends
+    pub ranges: Vec<AddressRange>,
+}
+
+impl FunctionFilter {
+    /// Whether `func` should be traced, `file_address` is its address in the object `object`
+    pub fn keep(&self, func: &Function, object: &str, file_address: u64) -> bool {
+        fn any<T>(filters: &[T], f: impl Fn(&T) -> bool) -> bool {
+            filters.is_empty() || filters.iter().any(f)
+        }
+        let matches_path = |globs: &[Glob], path: &Option<String>| {
+            any(
+                globs,
+                |glob| matches!(path, Some(path) if glob.is_match(path)),
+            )
+        };
+
+        self.only
+            .as_ref()
+            .is_none_or(|only| only.is_match(&func.name))
+            && !self
+                .ignore
+                .as_ref()
+                .is_some_and(|ignore| ignore.is_match(&func.name))
+            && !self
+                .runtime
+                .as_ref()
+                .is_some_and(|runtime| runtime.is_match(&func.name))
+            && matches_path(&self.files, &func.decl_file)
+            && matches_path(&self.units, &func.unit)
+            && any(&self.crates, |name| {
+                crate_name(&func.name) == Some(name.as_str())
+            })
+            && any(&self.objects, |glob| glob.is_match(object))
+            && any(&self.ranges, |range| range.contains(file_address))
+    }
+}
+
+/// The crate of a demangled Rust path, e.g. `core` for `<core::fmt::Arguments as Display>::fmt`
+fn crate_name(name: &str) -> Option<&str> {
+    let (name, _) = name.trim_start_matches('<').split_once("::")?;
+    Some(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn glob(s: &str) -> Glob {
+        s.parse().unwrap()
+    }
+
+    fn function(name: &str, decl_file: Option<&str>) -> Function {
+        Function {
+            address: 0x1000,
+            prologue_end_addr: None,
+            name: name.to_owned(),
+            parameters: vec![],
+            return_type: None,
+            inlined: false,
+            exit_addrs: vec![],
+            decl_file: decl_file.map(str::to_owned),
+            decl_line: None,
+            unit: None,
+        }
+    }
+
+    #[test]
+    fn globs_match_path_components() {
+        assert!(glob("*.c").is_match("tcp.c"));
+        assert!(glob("*.c").is_match("/src/net/tcp.c"));
+        assert!(!glob("*.c").is_match("/src/net/tcp.cc"));
+        assert!(glob("net/*.c").is_match("/src/net/tcp.c"));
+        assert!(!glob("net/*.c").is_match("/src/net/ipv4/tcp.c"));
+        assert!(!glob("net/*.c").is_match("/src/subnet/tcp.c"));
+        assert!(glob("net/**.c").is_match("/src/net/ipv4/tcp.c"));
+        assert!(glob("tcp?.c").is_match("tcp4.c"));
+        assert!(!glob("tcp?.c").is_match("tcp.c"));
+    }
+
+    #[test]
+    fn absolute_globs_match_from_the_root() {
+        assert!(glob("/src/*.c").is_match("/src/main.c"));
+        assert!(!glob("/src/*.c").is_match("/home/src/main.c"));
+        // regex characters are taken literally
+        assert!(glob("/usr/lib/libc++.so").is_match("/usr/lib/libc++.so"));
+        assert!(!glob("/usr/lib/libc++.so").is_match("/usr/lib/libcc.so"));
+    }
+
+    #[test]
+    fn parses_address_ranges() {
+        let range: AddressRange = "0x1000-0x2000".parse().unwrap();
+        assert!(!range.contains(0xfff));
+        assert!(range.contains(0x1000));
+        assert!(range.contains(0x1fff));
+        assert!(!range.contains(0x2000));
+
+        let range: AddressRange = "0x1000+0x10".parse().unwrap();
+        assert!(range.contains(0x100f));
+        assert!(!range.contains(0x1010));
+        // the addresses are hexadecimal with or without 0x
+        let range: AddressRange = "1000 - 2000".parse().unwrap();
+        assert!(range.contains(0x1000) && !range.contains(0x2000));
+    }
+
+    #[test]
+    fn rejects_invalid_address_ranges() {
+        for (range, error) in [
+            ("0x1000", "expected <start>-<end> or <start>+<len>"),
+            ("0x1000-", "invalid address"),
+            ("zz-0x2000", "invalid address"),
+            ("0x1000+len", "invalid address"),
+        ] {
+            assert_eq!(
+                range.parse::<AddressRange>().unwrap_err(),
+                error,
+                "{}",
+                range
+            );
+        }
+    }
+
+    #[test]
+    fn every_kind_of_filter_has_to_match() {
+        let filter = FunctionFilter {
+            only: Some(Regex::new("^net_").unwrap()),
+            files: vec![glob("net/*.c"), glob("proto/*.c")],
+            ranges: vec!["0x1000+0x100".parse().unwrap()],
+            ..Default::default()
+        };
+        let keep = |name, file, address| filter.keep(&function(name, file), "/bin/app", address);
+        assert!(keep("net_send", Some("/src/net/tcp.c"), 0x1000));
+        assert!(keep("net_send", Some("/src/proto/http.c"), 0x1000));
+        assert!(!keep("send", Some("/src/net/tcp.c"), 0x1000));
+        assert!(!keep("net_send", Some("/src/disk/io.c"), 0x1000));
+        // functions without a declaration file don't match file filters
+        assert!(!keep("net_send", None, 0x1000));
+        assert!(!keep("net_send", Some("/src/net/tcp.c"), 0x1100));
+    }
+
+    #[test]
+    fn ignored_and_runtime_functions_are_dropped() {
+        let filter = FunctionFilter {
+            ignore: Some(Regex::new("debug").unwrap()),
+            runtime: Some(runtime_functions()),
+            objects: vec![glob("app")],
+            ..Default::default()
+        };
+        let keep = |name| filter.keep(&function(name, None), "/bin/app", 0);
+        assert!(keep("main"));
+        assert!(!keep("debug_dump"));
+        assert!(!keep("_start"));
+        assert!(!keep("_GLOBAL__sub_I_main"));
+        assert!(!keep("std::rt::lang_start_internal"));
+        // generic std code also runs the user's closures
+        assert!(keep("core::ops::function::FnOnce::call_once"));
+        assert!(keep("std::panicking::try"));
+        assert!(!filter.keep(&function("main", None), "/bin/other", 0));
+    }
+
+    #[test]
+    fn filters_rust_crates() {
+        let filter = FunctionFilter {
+            crates: vec!["app".to_owned()],
+            ..Default::default()
+        };
+        let keep = |name| filter.keep(&function(name, None), "/bin/app", 0);
+        assert!(keep("app::main"));
+        assert!(keep("<app::Config as core::fmt::Debug>::fmt"));
+        assert!(!keep("core::fmt::write"));
+        assert!(!keep("main"));
+    }
+}
@@ -0,0 +1,886 @@
+//! Data flow analysis of a single function's machine code
+//!
+//! The code is split into basic blocks, and a backwards liveness analysis over the
+//! control flow graph tells which argument registers are read before being written.
+//! A forward pass over the same graph tells what the `ret`s leave in RAX and XMM0.
+//!
+//! A jump to another function is a tail call: it reads the arguments of the function it
+//! jumps to, and returns whatever that function returns.
+
+use std::collections::{BTreeMap, VecDeque};
+
+use capstone::arch::x86::{X86OperandType, X86Reg};
+use capstone::arch::ArchOperand;
+use capstone::prelude::*;
+use tracing::debug;
+
+use crate::defs::Register;
+use crate::function::{
+    BaseType, BaseTypeEncoding, FormalParameter, FormalParameterKind, MemoryParam, TypeKind,
+};
+use crate::utils::parse_address;
+
+/// Integer argument registers of the System V ABI, in argument order
+const INT_ARG_REGS: [Register; 6] = [
+    gimli::X86_64::RDI,
+    gimli::X86_64::RSI,
+    gimli::X86_64::RDX,
+    gimli::X86_64::RCX,
+    gimli::X86_64::R8,
+    gimli::X86_64::R9,
+];
+/// Number of XMM registers used to pass floating point arguments
+const N_XMM_ARGS: u8 = 8;
+
+// Registers are tracked as bits of a u32:
+// bits 0..6 are the integer argument registers, bit 6 is RAX, bits 8..16 are XMM0-XMM7
+const RAX_BIT: u32 = 1 << 6;
+const XMM_SHIFT: u32 = 8;
+const INT_ARGS_MASK: u32 = 0b11_1111;
+const XMM_ARGS_MASK: u32 = 0xff << XMM_SHIFT;
+/// Registers that a call may overwrite
+const CALLER_SAVED_MASK: u32 = INT_ARGS_MASK | RAX_BIT | XMM_ARGS_MASK;
+/// Registers that a call may read
+const ARGS_MASK: u32 = INT_ARGS_MASK | XMM_ARGS_MASK;
+const XMM0_BIT: u32 = 1 << XMM_SHIFT;
+
+/// How a return register got its value
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Writer {
+    /// Written by the function itself, with the width in bytes
+    Explicit(u8),
+    /// Left over from a call
+    Call,
+}
+
+/// What a function leaves in the return registers
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReturnKind {
+    Void,
+    /// Integer of the given width in bytes
+    Int(u8),
+    /// Float of the given width in bytes
+    Float(u8),
+    /// Returns whatever a function it called returned
+    PassThrough,
+}
+
+/// A direct call and whether the caller looks at the return registers afterwards
+#[derive(Debug)]
+pub struct CallSite {
+    pub target: u64,
+    pub rax_used: bool,
+    pub xmm0_used: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Flow {
+    /// Falls through to the next instruction
+    Next,
+    /// Calls a function and then falls through
+    Call,
+    /// Jumps unconditionally, the target is known if it is inside the function
+    Jump(Option<u64>),
+    /// Either jumps or falls through
+    CondJump(Option<u64>),
+    /// Jumps to the function at the address, which returns in place of this one
+    TailCall(u64),
+    /// Either tail calls the function at the address or falls through
+    CondTailCall(u64),
+    Ret,
+    /// Never continues (`hlt`, `ud2`)
+    Stop,
+}
+
+/// What an instruction does to RSP
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum StackEffect {
+    None,
+    /// Adds a constant to RSP
+    Adjust(i64),
+    /// RSP is written in some way we don't follow
+    Clobber,
+}
+
+/// What an instruction does to RBP
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum FrameEffect {
+    None,
+    /// `mov rbp, rsp`
+    SetFromRsp,
+    /// RBP is written with something other than RSP
+    Clobber,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum StackBase {
+    Rsp,
+    Rbp,
+}
+
+/// A decoded instruction, reduced to what the analyses need
+#[derive(Debug)]
+struct Insn {
+    address: u64,
+    flow: Flow,
+    /// (register bit, width in bytes) of the registers read
+    reads: Vec<(u32, u8)>,
+    /// registers which are completely overwritten
+    defs: u32,
+    /// (base, displacement, size) of memory read relative to RSP or RBP
+    stack_reads: Vec<(StackBase, i64, u8)>,
+    rsp: StackEffect,
+    rbp: FrameEffect,
+    rax_write: Option<Writer>,
+    xmm0_write: Option<Writer>,
+    /// target of a direct call
+    call_target: Option<u64>,
+}
+
+#[derive(Debug)]
+struct Block {
+    /// range of indices into the instructions
+    start: usize,
+    end: usize,
+    succs: Vec<usize>,
+}
+
+/// Result of analysing a function
+#[derive(Debug)]
+pub struct FunctionAnalysis {
+    insns: Vec<Insn>,
+    blocks: Vec<Block>,
+    live_in: Vec<u32>,
+    /// widest read of each register which happens before it is written, by register bit
+    read_widths: [u8; 32],
+}
+
+/// The argument registers which the function at an address reads, if it is known
+pub type ArgumentUses<'a> = &'a dyn Fn(u64) -> Option<u32>;
+
+impl FunctionAnalysis {
+    pub fn new(cs: &Capstone, code: &[u8], address: u64) -> Self {
+        let insns = match cs.disasm_all(code, address) {
+            Ok(insns) => insns
+                .iter()
+                .map(|insn| decode_insn(cs, &insn, address, address + code.len() as u64))
+                .collect(),
+            Err(_) => vec![],
+        };
+        let blocks = build_blocks(&insns);
+        let mut analysis = Self {
+            insns,
+            blocks,
+            live_in: vec![],
+            read_widths: [0; 32],
+        };
+        analysis.compute_liveness(&|_| None);
+        analysis
+    }
+
+    /// The argument registers read before being written, as register bits
+    pub fn argument_uses(&self) -> u32 {
+        self.live_in.first().copied().unwrap_or(0) & ARGS_MASK
+    }
+
+    /// The functions this one jumps to
+    pub fn tail_calls(&self) -> impl Iterator<Item = u64> + '_ {
+        self.insns.iter().filter_map(|insn| match insn.flow {
+            Flow::TailCall(target) | Flow::CondTailCall(target) => Some(target),
+            _ => None,
+        })
+    }
+
+    /// Redoes the liveness analysis knowing which arguments the tail called functions read,
+    /// a tail call to an unknown function reads all the argument registers
+    pub fn resolve_tail_calls(&mut self, uses: ArgumentUses) {
+        if self.tail_calls().next().is_some() {
+            self.compute_liveness(uses);
+        }
+    }
+
+    /// Infers the formal parameters in ABI order: integer registers, XMM registers, stack
+    pub fn parameters(&self) -> Vec<FormalParameter> {
+        let live = self.argument_uses();
+        let mut params = vec![];
+
+        // an argument which is never read still takes its register, so fill the gaps
+        let n_int = 32 - (live & INT_ARGS_MASK).leading_zeros();
+        for i in 0..n_int {
+            params.push(FormalParameter {
+                name: None,
+                kind: FormalParameterKind::Register(INT_ARG_REGS[i as usize]),
+                ty: Some(TypeKind::BaseType(BaseType {
+                    size: self.width_or_default(i),
+                    encoding: BaseTypeEncoding::Unsigned,
+                })),
+            });
+        }
+
+        let n_xmm = 32 - ((live & XMM_ARGS_MASK) >> XMM_SHIFT).leading_zeros();
+        for i in 0..n_xmm {
+            params.push(FormalParameter {
+                name: None,
+                kind: FormalParameterKind::Register(xmm_register(i as u16)),
+                ty: Some(TypeKind::BaseType(BaseType {
+                    size: self.width_or_default(XMM_SHIFT + i),
+                    encoding: BaseTypeEncoding::Float,
+                })),
+            });
+        }
+
+        for (offset, size) in self.stack_args() {
+            params.push(FormalParameter {
+                name: None,
+                kind: FormalParameterKind::Stack(MemoryParam { offset, size }),
+                ty: None,
+            });
+        }
+        debug!(?params, "inferred params");
+        params
+    }
+
+    /// Classifies what the `ret`s of the function leave in RAX and XMM0
+    pub fn return_kind(&self) -> ReturnKind {
+        if self.blocks.is_empty() {
+            return ReturnKind::Void;
+        }
+        let mut state: Vec<Option<ReturnRegs>> = vec![None; self.blocks.len()];
+        state[0] = Some(ReturnRegs::default());
+        let mut at_ret: Option<ReturnRegs> = None;
+
+        let mut queue = VecDeque::from(vec![0]);
+        while let Some(b) = queue.pop_front() {
+            let mut regs = state[b].unwrap();
+            let block = &self.blocks[b];
+            for insn in &self.insns[block.start..block.end] {
+                regs.update(insn);
+                let returned = match insn.flow {
+                    Flow::Ret => regs,
+                    // whatever the other function returns
+                    Flow::TailCall(_) | Flow::CondTailCall(_) => ReturnRegs {
+                        rax: Some(Writer::Call),
+                        xmm0: Some(Writer::Call),
+                        float_written_last: false,
+                    },
+                    _ => continue,
+                };
+                at_ret = Some(at_ret.map_or(returned, |r| r.join(returned)));
+            }
+            for &s in &block.succs {
+                let new = state[s].map_or(regs, |r| r.join(regs));
+                if state[s] != Some(new) {
+                    state[s] = Some(new);
+                    queue.push_back(s);
+                }
+            }
+        }
+
+        let regs = at_ret.unwrap_or_default();
+        match (regs.rax, regs.xmm0) {
+            (_, Some(Writer::Explicit(width))) if regs.float_written_last => {
+                ReturnKind::Float(width)
+            }
+            (Some(Writer::Explicit(width)), _) => ReturnKind::Int(width),
+            (_, Some(Writer::Explicit(width))) => ReturnKind::Float(width),
+            (None, None) => ReturnKind::Void,
+            _ => ReturnKind::PassThrough,
+        }
+    }
+
+    /// Finds the direct calls and whether the return registers are read after them
+    pub fn call_sites(&self) -> Vec<CallSite> {
+        let mut sites = vec![];
+        for (b, block) in self.blocks.iter().enumerate() {
+            let mut live = self.blocks[b]
+                .succs
+                .iter()
+                .fold(0, |acc, &s| acc | self.live_in[s]);
+            for insn in self.insns[block.start..block.end].iter().rev() {
+                if let Some(target) = insn.call_target {
+                    sites.push(CallSite {
+                        target,
+                        rax_used: live & RAX_BIT != 0,
+                        xmm0_used: live & XMM0_BIT != 0,
+                    });
+                }
+                live &= !insn.defs;
+                for &(bit, _) in &insn.reads {
+                    live |= bit;
+                }
+            }
+        }
+        sites
+    }
+
+    fn width_or_default(&self, bit: u32) -> u64 {
+        match self.read_widths[bit as usize] {
+            0 => 8,
+            width => width as u64,
+        }
+    }
+
+    fn compute_liveness(&mut self, tail_uses: ArgumentUses) {
+        let n = self.blocks.len();
+        let mut uses = vec![0u32; n];
+        let mut defs = vec![0u32; n];
+        for (b, block) in self.blocks.iter().enumerate() {
+            for insn in &self.insns[block.start..block.end] {
+                for &(bit, _) in &insn.reads {
+                    if defs[b] & bit == 0 {
+                        uses[b] |= bit;
+                    }
+                }
+                if let Flow::TailCall(target) | Flow::CondTailCall(target) = insn.flow {
+                    uses[b] |= tail_uses(target).unwrap_or(ARGS_MASK) & ARGS_MASK & !defs[b];
+                }
+                defs[b] |= insn.defs;
+            }
+        }
+
+        self.live_in = vec![0; n];
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for b in (0..n).rev() {
+                let live_out = self.blocks[b]
+                    .succs
+                    .iter()
+                    .fold(0, |acc, &s| acc | self.live_in[s]);
+                let live_in = uses[b] | (live_out & !defs[b]);
+                if live_in != self.live_in[b] {
+                    self.live_in[b] = live_in;
+                    changed = true;
+                }
+            }
+        }
+
+        // widths of the reads which see the value the register had at the start of the block
+        for (b, block) in self.blocks.iter().enumerate() {
+            let mut written = 0;
+            for insn in &self.insns[block.start..block.end] {
+                for &(bit, width) in &insn.reads {
+                    if written & bit == 0 && self.live_in[b] & bit != 0 {
+                        let idx = bit.trailing_zeros() as usize;
+                        self.read_widths[idx] = self.read_widths[idx].max(width);
+                    }
+                }
+                written |= insn.defs;
+            }
+        }
+    }
+
+    /// Finds reads above the return address, relative to RSP at the function entry
+    fn stack_args(&self) -> BTreeMap<i64, u64> {
+        let mut args = BTreeMap::new();
+        if self.blocks.is_empty() {
+            return args;
+        }
+
+        // (rsp, rbp) relative to the RSP at function entry, when known
+        let mut state: Vec<Option<(Option<i64>, Option<i64>)>> = vec![None; self.blocks.len()];
+        state[0] = Some((Some(0), None));
+        let mut queue = VecDeque::from(vec![0]);
+        while let Some(b) = queue.pop_front() {
+            let (mut rsp, mut rbp) = state[b].unwrap();
+            let block = &self.blocks[b];
+            for insn in &self.insns[block.start..block.end] {
+                for &(base, disp, size) in &insn.stack_reads {
+                    let base = match base {
+                        StackBase::Rsp => rsp,
+                        StackBase::Rbp => rbp,
+                    };
+                    // past the return address at entry RSP
+                    if let Some(offset) = base.map(|b| b + disp - 8).filter(|&o| o >= 0) {
+                        let arg_size = args.entry(offset).or_insert(0);
+                        *arg_size = (*arg_size).max(size as u64);
+                    }
+                }
+                match insn.rbp {
+                    FrameEffect::None => {}
+                    FrameEffect::SetFromRsp => rbp = rsp,
+                    FrameEffect::Clobber => rbp = None,
+                }
+                match insn.rsp {
+                    StackEffect::None => {}
+                    StackEffect::Adjust(n) => rsp = rsp.map(|rsp| rsp + n),
+                    StackEffect::Clobber => rsp = None,
+                }
+            }
+            for &s in &block.succs {
+                if state[s].is_none() {
+                    state[s] = Some((rsp, rbp));
+                    queue.push_back(s);
+                }
+            }
+        }
+        args
+    }
+}
+
+fn xmm_register(n: u16) -> Register {
+    Register(gimli::X86_64::XMM0.0 + n)
+}
+
+/// Maps a capstone register to its tracked bit and the width of the access in bytes
+fn classify_reg(reg: RegId) -> Option<(u32, u8)> {
+    use X86Reg::*;
+
+    let reg = reg.0 as u32;
+    let (idx, width) = match reg {
+        X86_REG_RDI => (0, 8),
+        X86_REG_EDI => (0, 4),
+        X86_REG_DI => (0, 2),
+        X86_REG_DIL => (0, 1),
+        X86_REG_RSI => (1, 8),
+        X86_REG_ESI => (1, 4),
+        X86_REG_SI => (1, 2),
+        X86_REG_SIL => (1, 1),
+        X86_REG_RDX => (2, 8),
+        X86_REG_EDX => (2, 4),
+        X86_REG_DX => (2, 2),
+        X86_REG_DL | X86_REG_DH => (2, 1),
+        X86_REG_RCX => (3, 8),
+        X86_REG_ECX => (3, 4),
+        X86_REG_CX => (3, 2),
+        X86_REG_CL | X86_REG_CH => (3, 1),
+        X86_REG_R8 => (4, 8),
+        X86_REG_R8D => (4, 4),
+        X86_REG_R8W => (4, 2),
+        X86_REG_R8B => (4, 1),
+        X86_REG_R9 => (5, 8),
+        X86_REG_R9D => (5, 4),
+        X86_REG_R9W => (5, 2),
+        X86_REG_R9B => (5, 1),
+        X86_REG_RAX => (6, 8),
+        X86_REG_EAX => (6, 4),
+        X86_REG_AX => (6, 2),
+        X86_REG_AL | X86_REG_AH => (6, 1),
+        r if (X86_REG_XMM0..X86_REG_XMM0 + N_XMM_ARGS as u32).contains(&r) => {
+            (XMM_SHIFT + r - X86_REG_XMM0, 16)
+        }
+        _ => return None,
+    };
+    Some((1 << idx, width))
+}
+
+fn is_reg(reg: RegId, x86_reg: u32) -> bool {
+    reg.0 as u32 == x86_reg
+}
+
+fn decode_insn(cs: &Capstone, insn: &capstone::Insn, start: u64, end: u64) -> Insn {
+    use X86Reg::*;
+
+    let mnemonic = insn.mnemonic().unwrap_or("");
+    let direct = insn.op_str().and_then(|op| parse_address(op).ok());
+    let target = direct.filter(|&t| t >= start && t < end);
+    // a direct jump out of the function is a tail call
+    let outside = direct.filter(|_| target.is_none());
+    let flow = match (mnemonic, outside) {
+        (m, _) if m.ends_with("ret") => Flow::Ret,
+        ("hlt" | "ud2", _) => Flow::Stop,
+        ("call", _) => Flow::Call,
+        ("jmp", Some(outside)) => Flow::TailCall(outside),
+        ("jmp", None) => Flow::Jump(target),
+        (m, Some(outside)) if m.starts_with('j') => Flow::CondTailCall(outside),
+        (m, _) if m.starts_with('j') || m.starts_with("loop") => Flow::CondJump(target),
+        _ => Flow::Next,
+    };
+
+    let mut decoded = Insn {
+        address: insn.address(),
+        flow,
+        reads: vec![],
+        defs: 0,
+        stack_reads: vec![],
+        rsp: StackEffect::None,
+        rbp: FrameEffect::None,
+        rax_write: None,
+        xmm0_write: None,
+        call_target: None,
+    };
+    let detail = match cs.insn_detail(insn) {
+        Ok(detail) => detail,
+        Err(_) => return decoded,
+    };
+    // scalar float instructions tell the width of the XMM access
+    let xmm_width = if mnemonic.ends_with("ss") { 4 } else { 8 };
+    let reg_access = |reg: RegId| {
+        classify_reg(reg).map(|(bit, width)| {
+            if bit & XMM_ARGS_MASK != 0 {
+                (bit, xmm_width)
+            } else {
+                (bit, width)
+            }
+        })
+    };
+
+    for reg in detail.regs_read() {
+        decoded.reads.extend(reg_access(*reg));
+    }
+    for reg in detail.regs_write() {
+        if let Some((bit, width)) = reg_access(*reg) {
+            decoded.record_write(bit, width);
+        }
+    }
+
+    let operands: Vec<_> = detail
+        .arch_detail()
+        .operands()
+        .into_iter()
+        .filter_map(|op| match op {
+            ArchOperand::X86Operand(op) => Some(op),
+            _ => None,
+        })
+        .collect();
+
+    // `xor eax, eax` and friends don't depend on the old value
+    let zero_idiom = matches!(mnemonic, "xor" | "sub" | "pxor" | "xorps" | "xorpd")
+        && operands.len() == 2
+        && operands[0].op_type == operands[1].op_type;
+
+    for op in operands.iter() {
+        let readable = op.access.is_none_or(|a| a.is_readable());
+        let writable = op.access.is_some_and(|a| a.is_writable());
+        match op.op_type {
+            X86OperandType::Reg(reg) => {
+                if readable && !zero_idiom {
+                    decoded.reads.extend(reg_access(reg));
+                }
+                if writable {
+                    if let Some((bit, width)) = reg_access(reg) {
+                        decoded.record_write(bit, width);
+                    }
+                    if is_reg(reg, X86_REG_RBP) {
+                        decoded.rbp = FrameEffect::Clobber;
+                    } else if is_reg(reg, X86_REG_RSP) {
+                        decoded.rsp = StackEffect::Clobber;
+                    }
+                }
+            }
+            X86OperandType::Mem(mem) => {
+                for reg in [mem.base(), mem.index()] {
+                    decoded.reads.extend(reg_access(reg));
+                }
+                if readable && mnemonic != "lea" && mem.index().0 == 0 {
+                    let base = if is_reg(mem.base(), X86_REG_RSP) {
+                        Some(StackBase::Rsp)
+                    } else if is_reg(mem.base(), X86_REG_RBP) {
+                        Some(StackBase::Rbp)
+                    } else {
+                        None
+                    };
+                    if let Some(base) = base {
+                        decoded.stack_reads.push((base, mem.disp(), op.size));
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let is_op_reg = |op: &capstone::arch::x86::X86Operand, x86_reg: u32| matches!(op.op_type, X86OperandType::Reg(reg) if is_reg(reg, x86_reg));
+    match (mnemonic, operands.as_slice()) {
+        ("push", _) => decoded.rsp = StackEffect::Adjust(-8),
+        ("pop", _) => decoded.rsp = StackEffect::Adjust(8),
+        ("sub", [dst, src]) | ("add", [dst, src]) if is_op_reg(dst, X86_REG_RSP) => {
+            decoded.rsp = match src.op_type {
+                X86OperandType::Imm(n) if mnemonic == "sub" => StackEffect::Adjust(-n),
+                X86OperandType::Imm(n) => StackEffect::Adjust(n),
+                _ => StackEffect::Clobber,
+            }
+        }
+        ("mov", [dst, src]) if is_op_reg(dst, X86_REG_RBP) && is_op_reg(src, X86_REG_RSP) => {
+            decoded.rbp = FrameEffect::SetFromRsp
+        }
+        _ => {}
+    }
+
+    if flow == Flow::Call {
+        decoded.defs |= CALLER_SAVED_MASK;
+        decoded.rax_write = Some(Writer::Call);
+        decoded.xmm0_write = Some(Writer::Call);
+        decoded.call_target = insn.op_str().and_then(|op| parse_address(op).ok());
+    }
+    decoded
+}
+
+impl Insn {
+    fn record_write(&mut self, bit: u32, width: u8) {
+        // 8 and 16 bit writes keep the rest of the register
+        if width >= 4 {
+            self.defs |= bit;
+        }
+        if bit == RAX_BIT {
+            self.rax_write = Some(Writer::Explicit(width));
+        } else if bit == XMM0_BIT {
+            self.xmm0_write = Some(Writer::Explicit(width));
+        }
+    }
+}
+
+/// What the return registers hold at some point of the function
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+struct ReturnRegs {
+    rax: Option<Writer>,
+    xmm0: Option<Writer>,
+    /// XMM0 was written explicitly after RAX on some path
+    float_written_last: bool,
+}
+
+impl ReturnRegs {
+    fn update(&mut self, insn: &Insn) {
+        match (insn.rax_write, insn.xmm0_write) {
+            (Some(Writer::Explicit(_)), _) => self.float_written_last = false,
+            (_, Some(Writer::Explicit(_))) => self.float_written_last = true,
+            _ => {}
+        }
+        self.rax = insn.rax_write.or(self.rax);
+        self.xmm0 = insn.xmm0_write.or(self.xmm0);
+    }
+
+    fn join(self, other: Self) -> Self {
+        Self {
+            rax: join_writers(self.rax, other.rax),
+            xmm0: join_writers(self.xmm0, other.xmm0),
+            float_written_last: self.float_written_last || other.float_written_last,
+        }
+    }
+}
+
+/// Merges what two paths left in a register, preferring values the function wrote itself
+fn join_writers(a: Option<Writer>, b: Option<Writer>) -> Option<Writer> {
+    match (a, b) {
+        (None, x) | (x, None) => x,
+        (Some(Writer::Explicit(x)), Some(Writer::Explicit(y))) => Some(Writer::Explicit(x.max(y))),
+        (Some(Writer::Explicit(x)), _) | (_, Some(Writer::Explicit(x))) => {
+            Some(Writer::Explicit(x))
+        }
+        _ => Some(Writer::Call),
+    }
+}
+
+/// Splits the instructions into basic blocks and links them up
+fn build_blocks(insns: &[Insn]) -> Vec<Block> {
+    if insns.is_empty() {
+        return vec![];
+    }
+    let index_of: BTreeMap<u64, usize> = insns
+        .iter()
+        .enumerate()
+        .map(|(i, insn)| (insn.address, i))
+        .collect();
+
+    let mut leaders = vec![false; insns.len()];
+    leaders[0] = true;
+    for (i, insn) in insns.iter().enumerate() {
+        match insn.flow {
+            Flow::Next | Flow::Call => continue,
+            Flow::Jump(Some(target)) | Flow::CondJump(Some(target)) => {
+                // a target in the middle of an instruction is ignored
+                if let Some(&t) = index_of.get(&target) {
+                    leaders[t] = true;
+                }
+            }
+            _ => {}
+        }
+        if i + 1 < insns.len() {
+            leaders[i + 1] = true;
+        }
+    }
+
+    let starts: Vec<usize> = (0..insns.len()).filter(|&i| leaders[i]).collect();
+    let block_of: BTreeMap<usize, usize> =
+        starts.iter().enumerate().map(|(b, &i)| (i, b)).collect();
+    let block_at = |address: u64| {
+        index_of
+            .get(&address)
+            .and_then(|i| block_of.get(i).copied())
+    };
+
+    starts
+        .iter()
+        .enumerate()
+        .map(|(b, &start)| {
+            let end = starts.get(b + 1).copied().unwrap_or(insns.len());
+            let fallthrough = if end < insns.len() { Some(b + 1) } else { None };
+            let succs = match insns[end - 1].flow {
+                Flow::Next | Flow::Call | Flow::CondTailCall(_) => {
+                    fallthrough.into_iter().collect()
+                }
+                Flow::CondJump(target) => fallthrough
+                    .into_iter()
+                    .chain(target.and_then(block_at))
+                    .collect(),
+                Flow::Jump(target) => target.and_then(block_at).into_iter().collect(),
+                Flow::Ret | Flow::Stop | Flow::TailCall(_) => vec![],
+            };
+            Block { start, end, succs }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::function::heuristic::new_capstone;
+
+    fn analyse(code: &[u8]) -> FunctionAnalysis {
+        FunctionAnalysis::new(&new_capstone(true), code, 0x1000)
+    }
+
+    /// The registers of the inferred parameters and their sizes
+    fn registers(analysis: &FunctionAnalysis) -> Vec<(Register, u64)> {
+        analysis
+            .parameters()
+            .iter()
+            .map(|param| match (&param.kind, &param.ty) {
+                (FormalParameterKind::Register(register), Some(TypeKind::BaseType(ty))) => {
+                    (*register, ty.size)
+                }
+                other => panic!("not a register parameter: {:?}", other),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn reads_before_writes_are_parameters() {
+        // lea eax, [rdi + rsi]; ret
+        let analysis = analyse(&[0x8d, 0x04, 0x37, 0xc3]);
+        assert_eq!(
+            registers(&analysis),
+            [(gimli::X86_64::RDI, 8), (gimli::X86_64::RSI, 8)]
+        );
+        assert_eq!(analysis.return_kind(), ReturnKind::Int(4));
+    }
+
+    #[test]
+    fn unread_registers_before_the_last_parameter_are_counted() {
+        // mov eax, edx; ret
+        let analysis = analyse(&[0x89, 0xd0, 0xc3]);
+        assert_eq!(
+            registers(&analysis),
+            [
+                (gimli::X86_64::RDI, 8),
+                (gimli::X86_64::RSI, 8),
+                (gimli::X86_64::RDX, 4),
+            ]
+        );
+    }
+
+    #[test]
+    fn registers_written_first_are_not_parameters() {
+        // mov edi, 1; mov eax, edi; ret
+        let analysis = analyse(&[0xbf, 0x01, 0x00, 0x00, 0x00, 0x89, 0xf8, 0xc3]);
+        assert!(analysis.parameters().is_empty());
+        // xor eax, eax; ret
+        let analysis = analyse(&[0x31, 0xc0, 0xc3]);
+        assert!(analysis.parameters().is_empty());
+        assert_eq!(analysis.return_kind(), ReturnKind::Int(4));
+    }
+
+    #[test]
+    fn calls_clobber_the_argument_registers() {
+        // call 0x2000; mov eax, edi; ret
+        let analysis = analyse(&[0xe8, 0xfb, 0x0f, 0x00, 0x00, 0x89, 0xf8, 0xc3]);
+        assert!(analysis.parameters().is_empty());
+        let sites = analysis.call_sites();
+        assert_eq!(sites.len(), 1);
+        assert_eq!(sites[0].target, 0x2000);
+        assert!(!sites[0].rax_used);
+    }
+
+    #[test]
+    fn parameters_read_on_any_path_are_live() {
+        // test edi, edi; je 1f; mov eax, esi; ret; 1: xor eax, eax; ret
+        let code = [0x85, 0xff, 0x74, 0x03, 0x89, 0xf0, 0xc3, 0x31, 0xc0, 0xc3];
+        let analysis = analyse(&code);
+        assert_eq!(
+            registers(&analysis),
+            [(gimli::X86_64::RDI, 4), (gimli::X86_64::RSI, 4)]
+        );
+    }
+
+    #[test]
+    fn float_parameters() {
+        // addsd xmm0, xmm1; ret
+        let analysis = analyse(&[0xf2, 0x0f, 0x58, 0xc1, 0xc3]);
+        assert_eq!(
+            registers(&analysis),
+            [(xmm_register(0), 8), (xmm_register(1), 8)]
+        );
+        assert_eq!(analysis.return_kind(), ReturnKind::Float(8));
+    }
+
+    #[test]
+    fn stack_parameters_are_above_the_return_address() {
+        // mov rax, [rsp + 8]; mov rcx, [rsp + 0x10]; ret
+        let code = [
+            0x48, 0x8b, 0x44, 0x24, 0x08, 0x48, 0x8b, 0x4c, 0x24, 0x10, 0xc3,
+        ];
+        let offsets: Vec<_> = analyse(&code)
+            .parameters()
+            .iter()
+            .map(|param| match &param.kind {
+                FormalParameterKind::Stack(memory) => (memory.offset, memory.size),
+                other => panic!("not a stack parameter: {:?}", other),
+            })
+            .collect();
+        assert_eq!(offsets, [(0, 8), (8, 8)]);
+    }
+
+    #[test]
+    fn empty_function() {
+        // ret
+        let analysis = analyse(&[0xc3]);
+        assert!(analysis.parameters().is_empty());
+        assert_eq!(analysis.return_kind(), ReturnKind::Void);
+    }
+
+    #[test]
+    fn tail_calls_read_the_arguments_of_their_target() {
+        // mov esi, 5; jmp 0x2000
+        let code = [0xbe, 0x05, 0x00, 0x00, 0x00, 0xe9, 0xf6, 0x0f, 0x00, 0x00];
+        let mut analysis = analyse(&code);
+        assert_eq!(analysis.tail_calls().collect::<Vec<_>>(), [0x2000]);
+        // not knowing the target, every argument register but the one written is read
+        assert_eq!(analysis.argument_uses(), ARGS_MASK & !(1 << 1));
+        assert_eq!(analysis.return_kind(), ReturnKind::PassThrough);
+
+        analysis.resolve_tail_calls(&|target| (target == 0x2000).then_some(0b11));
+        assert_eq!(registers(&analysis), [(gimli::X86_64::RDI, 8)]);
+    }
+
+    #[test]
+    fn wrappers_return_what_they_tail_call() {
+        // lea edi, [rdi + 1]; jmp 0x2000
+        let mut analysis = analyse(&[0x8d, 0x7f, 0x01, 0xe9, 0xf8, 0x0f, 0x00, 0x00]);
+        analysis.resolve_tail_calls(&|_| Some(0b1));
+        assert_eq!(registers(&analysis), [(gimli::X86_64::RDI, 8)]);
+        assert_eq!(analysis.return_kind(), ReturnKind::PassThrough);
+
+        // test edi, edi; je 0x2000; xor eax, eax; ret
+        let code = [
+            0x85, 0xff, 0x0f, 0x84, 0xf8, 0x0f, 0x00, 0x00, 0x31, 0xc0, 0xc3,
+        ];
+        let mut analysis = analyse(&code);
+        analysis.resolve_tail_calls(&|_| Some(0b11));
+        assert_eq!(
+            registers(&analysis),
+            [(gimli::X86_64::RDI, 4), (gimli::X86_64::RSI, 8)]
+        );
+        assert_eq!(analysis.return_kind(), ReturnKind::Int(4));
+    }
+
+    #[test]
+    fn jumps_inside_the_function_are_not_tail_calls() {
+        // test edi, edi; jne 1f; xor eax, eax; jmp 2f; 1: mov eax, 1; 2: ret
+        let code = [
+            0x85, 0xff, 0x75, 0x04, 0x31, 0xc0, 0xeb, 0x05, 0xb8, 0x01, 0x00, 0x00, 0x00, 0xc3,
+        ];
+        let analysis = analyse(&code);
+        assert_eq!(analysis.tail_calls().count(), 0);
+        assert_eq!(registers(&analysis), [(gimli::X86_64::RDI, 4)]);
+        assert_eq!(analysis.return_kind(), ReturnKind::Int(4));
+    }
+}
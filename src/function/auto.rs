@@ -0,0 +1,185 @@
+//! The `auto` function source, which merges every source of functions in the binary
+//!
+//! Each source knows something the others don't: DWARF has the parameters and types, the
+//! symbol tables have the functions without debug info and their qualified names, and
+//! `.eh_frame` still has the function starts once the binary is stripped.
+
+use std::collections::{BTreeMap, HashSet};
+
+use object::Object;
+use tracing::{debug, warn};
+
+use crate::function::{
+    discover_functions, get_dynamic_functions, get_functions, get_functions_dwarf, Function,
+};
+
+/// Gets the functions from every source available in the binary
+///
+/// The sources are tried from the richest to the poorest: DWARF, `.symtab`, `.dynsym`, and
+/// for stripped binaries the functions recovered by [`discover_functions`]. Functions are
+/// de-duplicated by address. Debug info which can't be read is skipped.
+pub fn get_functions_auto(
+    filename: &str,
+    obj: &object::File,
+) -> crate::defs::Result<Vec<Function>> {
+    let mut funcs: BTreeMap<u64, Function> = BTreeMap::new();
+    // the functions with debug info, whose parameters are known even when there are none
+    let mut typed = HashSet::new();
+
+    if obj.section_by_name(".debug_info").is_some() {
+        match get_functions_dwarf(filename, obj) {
+            Ok(dwarf_funcs) => {
+                typed.extend(dwarf_funcs.iter().map(|func| func.address));
+                merge_functions(&mut funcs, dwarf_funcs, &typed);
+            }
+            Err(err) => warn!(
+                ?err,
+                "could not read the functions from DWARF, using the symbols"
+            ),
+        }
+    }
+    let symtab_funcs = get_functions(obj);
+    let stripped = symtab_funcs.is_empty();
+    merge_functions(&mut funcs, symtab_funcs, &typed);
+    merge_functions(&mut funcs, get_dynamic_functions(obj), &typed);
+
+    if stripped {
+        merge_functions(&mut funcs, discover_functions(obj)?, &typed);
+    }
+    debug!(n_funcs = funcs.len(), ?stripped);
+
+    Ok(funcs.into_values().collect())
+}
+
+/// Adds `new` to `funcs`, filling in whatever the already known function is missing. The
+/// parameters of the functions at the `typed` addresses are never replaced
+fn merge_functions(funcs: &mut BTreeMap<u64, Function>, new: Vec<Function>, typed: &HashSet<u64>) {
+    for func in new {
+        let params_known = typed.contains(&func.address);
+        match funcs.get_mut(&func.address) {
+            Some(existing) => merge_function(existing, func, params_known),
+            None => {
+                funcs.insert(func.address, func);
+            }
+        }
+    }
+}
+
+/// Fills in what `existing` is missing from `other`, which starts at the same address
+fn merge_function(existing: &mut Function, other: Function, params_known: bool) {
+    // DWARF names C++ and Rust functions without their namespaces, the symbol demangles
+    // to the qualified name
+    if is_mangled(&other.name) && !is_mangled(&existing.name) {
+        existing.name = other.name;
+    }
+    if existing.parameters.is_empty() && !params_known {
+        existing.parameters = other.parameters;
+    }
+    if existing.prologue_end_addr.is_none() {
+        existing.prologue_end_addr = other.prologue_end_addr;
+    }
+}
+
+/// Whether `name` is a mangled C++ or Rust symbol
+fn is_mangled(name: &str) -> bool {
+    name.starts_with("_Z") || name.starts_with("_R")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::function::{synthetic_name, FormalParameter, FormalParameterKind};
+
+    fn function(address: u64, name: &str, parameters: usize) -> Function {
+        Function {
+            address,
+            prologue_end_addr: None,
+            name: name.to_owned(),
+            parameters: (0..parameters)
+                .map(|n| {
+                    Ok(FormalParameter {
+                        name: Some(format!("p{}", n)),
+                        kind: FormalParameterKind::Register(gimli::X86_64::RDI),
+                        ty: None,
+                    })
+                })
+                .collect(),
+            return_type: None,
+            inlined: false,
+            exit_addrs: vec![],
+            decl_file: None,
+            decl_line: None,
+            unit: None,
+        }
+    }
+
+    #[test]
+    fn prefers_the_qualified_symbol_name() {
+        let mut func = function(0x1000, "main", 0);
+        merge_function(
+            &mut func,
+            function(0x1000, "_ZN3app4main17h0123456789abcdefE", 0),
+            true,
+        );
+        assert_eq!(func.name, "_ZN3app4main17h0123456789abcdefE");
+
+        let mut func = function(0x1000, "_ZN3app4main17h0123456789abcdefE", 0);
+        merge_function(&mut func, function(0x1000, "main", 0), true);
+        assert_eq!(func.name, "_ZN3app4main17h0123456789abcdefE");
+
+        let mut func = function(0x1000, "fact", 0);
+        merge_function(&mut func, function(0x1000, "fact_alias", 0), false);
+        assert_eq!(func.name, "fact");
+    }
+
+    #[test]
+    fn known_parameters_are_kept() {
+        // DWARF saying there are no parameters beats the heuristic of the symbol
+        let mut func = function(0x1000, "noop", 0);
+        merge_function(&mut func, function(0x1000, "noop", 2), true);
+        assert!(func.parameters.is_empty());
+
+        let mut func = function(0x1000, "noop", 0);
+        merge_function(&mut func, function(0x1000, "noop", 2), false);
+        assert_eq!(func.parameters.len(), 2);
+
+        let mut func = function(0x1000, "sum", 1);
+        merge_function(&mut func, function(0x1000, "sum", 2), false);
+        assert_eq!(func.parameters.len(), 1);
+    }
+
+    #[test]
+    fn fills_in_the_prologue_end() {
+        let mut func = function(0x1000, "f", 0);
+        let mut other = function(0x1000, "f", 0);
+        other.prologue_end_addr = Some(0x1008);
+        merge_function(&mut func, other, false);
+        assert_eq!(func.prologue_end_addr, Some(0x1008));
+
+        let mut other = function(0x1000, "f", 0);
+        other.prologue_end_addr = Some(0x1004);
+        merge_function(&mut func, other, false);
+        assert_eq!(func.prologue_end_addr, Some(0x1008));
+    }
+
+    #[test]
+    fn discovered_functions_only_add_the_unknown_ones() {
+        let mut funcs = BTreeMap::new();
+        merge_functions(
+            &mut funcs,
+            vec![function(0x1000, "main", 0), function(0x1040, "fact", 1)],
+            &HashSet::new(),
+        );
+        let discovered = [0x1000, 0x1020, 0x1040]
+            .iter()
+            .map(|&address| function(address, &synthetic_name(address), 3))
+            .collect();
+        merge_functions(&mut funcs, discovered, &HashSet::new());
+
+        let names: Vec<_> = funcs.values().map(|func| func.name.as_str()).collect();
+        assert_eq!(names, ["main", "sub_1020", "fact"]);
+        // the parameters found by the heuristic fill in the ones the symbol didn't have
+        assert_eq!(funcs[&0x1000].parameters.len(), 3);
+        assert_eq!(funcs[&0x1040].parameters.len(), 1);
+    }
+}
@@ -0,0 +1,175 @@
+//! Finding the functions of stripped binaries
+//!
+//! Without symbols the function starts come from the unwind info, the entry point and the
+//! direct calls, see [`discover_functions`]. The same direct calls make up the call graph
+//! used to place breakpoints lazily.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use object::{Object, ObjectSection, SectionKind};
+use rayon::prelude::*;
+use tracing::debug;
+
+use crate::function::{eh_frame::get_fde_ranges, heuristic, synthetic_name, Function};
+use crate::utils::parse_address;
+
+/// Recovers the functions of a binary without any symbols
+///
+/// Function starts come from the `.eh_frame` FDEs, the entry point and the targets of direct
+/// `call`s found while disassembling the already known functions. They are named `sub_<addr>`.
+pub fn discover_functions(obj: &object::File) -> crate::defs::Result<Vec<Function>> {
+    let text = match obj.section_by_name(".text") {
+        Some(text) => text,
+        None => return Ok(vec![]),
+    };
+    let text_start = text.address();
+    let text_end = text_start + text.size();
+    let text_data = text.data()?;
+    let in_text = |addr: u64| addr >= text_start && addr < text_end;
+
+    // sizes are only known for functions which have an FDE
+    let fde_sizes: BTreeMap<u64, u64> = get_fde_ranges(obj)?
+        .into_iter()
+        .filter(|(addr, _)| in_text(*addr))
+        .collect();
+    let mut starts: BTreeSet<u64> = fde_sizes.keys().copied().collect();
+    if in_text(obj.entry()) {
+        starts.insert(obj.entry());
+    }
+    if starts.is_empty() {
+        // nothing to go on, sweep the whole section
+        starts.insert(text_start);
+    }
+
+    let cs = heuristic::new_capstone(false);
+    let mut worklist: Vec<u64> = starts.iter().copied().collect();
+    while let Some(start) = worklist.pop() {
+        let end = function_end(start, &fde_sizes, &starts, text_end);
+        let code = &text_data[(start - text_start) as usize..(end - text_start) as usize];
+        for target in direct_call_targets(&cs, code, start) {
+            if in_text(target) && starts.insert(target) {
+                worklist.push(target);
+            }
+        }
+    }
+    debug!(
+        n_fdes = fde_sizes.len(),
+        n_funcs = starts.len(),
+        "discovered functions"
+    );
+
+    let ranges = starts
+        .iter()
+        .map(|&start| {
+            let end = function_end(start, &fde_sizes, &starts, text_end);
+            (start, end - start, synthetic_name(start))
+        })
+        .collect();
+    Ok(heuristic::functions_from_ranges(obj, ranges))
+}
+
+/// A function ends where its FDE says so, or else at the next known function
+fn function_end(
+    start: u64,
+    fde_sizes: &BTreeMap<u64, u64>,
+    starts: &BTreeSet<u64>,
+    text_end: u64,
+) -> u64 {
+    if let Some(size) = fde_sizes.get(&start) {
+        return (start + size).min(text_end);
+    }
+    starts
+        .range(start + 1..)
+        .next()
+        .copied()
+        .unwrap_or(text_end)
+}
+
+/// Returns the targets of the direct `call`s in `code`
+fn direct_call_targets(cs: &capstone::Capstone, code: &[u8], address: u64) -> Vec<u64> {
+    let insns = match cs.disasm_all(code, address) {
+        Ok(insns) => insns,
+        Err(_) => return vec![],
+    };
+    insns
+        .iter()
+        .filter(|insn| insn.mnemonic() == Some("call"))
+        // only direct calls have an immediate as operand
+        .filter_map(|insn| insn.op_str().and_then(|op| parse_address(op).ok()))
+        .collect()
+}
+
+/// Maps each of the functions starting at `addresses` to the functions it calls directly
+///
+/// A function is assumed to span until the next one starts. Calls through pointers or
+/// into shared libraries are not seen.
+pub fn call_graph(obj: &object::File, addresses: &[u64]) -> HashMap<u64, Vec<u64>> {
+    let starts: BTreeSet<u64> = addresses.iter().copied().collect();
+    let sections: Vec<_> = obj
+        .sections()
+        .filter(|section| section.kind() == SectionKind::Text)
+        .filter_map(|section| Some((section.address(), section.data().ok()?)))
+        .collect();
+
+    starts
+        .par_iter()
+        .map_init(
+            || heuristic::new_capstone(false),
+            |cs, &start| {
+                let (section_start, data) = match sections
+                    .iter()
+                    .find(|(addr, data)| start >= *addr && start < addr + data.len() as u64)
+                {
+                    Some(section) => *section,
+                    None => return (start, vec![]),
+                };
+                let section_end = section_start + data.len() as u64;
+                let end = starts
+                    .range(start + 1..)
+                    .next()
+                    .map_or(section_end, |&next| next.min(section_end));
+                let code = &data[(start - section_start) as usize..(end - section_start) as usize];
+                let mut callees: Vec<_> = direct_call_targets(cs, code, start)
+                    .into_iter()
+                    .filter(|target| starts.contains(target))
+                    .collect();
+                callees.sort_unstable();
+                callees.dedup();
+                (start, callees)
+            },
+        )
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn functions_end_at_their_fde_or_the_next_start() {
+        let fde_sizes: BTreeMap<u64, u64> = [(0x1000, 0x20), (0x1080, 0x100)].into_iter().collect();
+        let starts: BTreeSet<u64> = [0x1000, 0x1010, 0x1040, 0x1080].into_iter().collect();
+        // the FDE wins over a start found inside of the function, e.g. a cold part
+        assert_eq!(function_end(0x1000, &fde_sizes, &starts, 0x1100), 0x1020);
+        assert_eq!(function_end(0x1010, &fde_sizes, &starts, 0x1100), 0x1040);
+        assert_eq!(function_end(0x1040, &fde_sizes, &starts, 0x1100), 0x1080);
+        // neither goes past the end of .text
+        assert_eq!(function_end(0x1080, &fde_sizes, &starts, 0x1100), 0x1100);
+        let starts: BTreeSet<u64> = [0x1040].into_iter().collect();
+        assert_eq!(
+            function_end(0x1040, &BTreeMap::new(), &starts, 0x1100),
+            0x1100
+        );
+    }
+
+    #[test]
+    fn finds_direct_call_targets() {
+        // call 0x2000; call rax; call qword ptr [rip + 0x10]; call 0x1000; ret
+        let code = [
+            0xe8, 0xfb, 0x0f, 0x00, 0x00, 0xff, 0xd0, 0xff, 0x15, 0x10, 0x00, 0x00, 0x00, 0xe8,
+            0xee, 0xff, 0xff, 0xff, 0xc3,
+        ];
+        let cs = heuristic::new_capstone(false);
+        assert_eq!(direct_call_targets(&cs, &code, 0x1000), [0x2000, 0x1000]);
+    }
+}
@@ -1,38 +1,49 @@
-use gimli::{AttributeValue, DW_AT_high_pc, DW_AT_low_pc, EvaluationResult};
+use gimli::{AttributeValue, DW_AT_low_pc, EvaluationResult};
 use object::{Object, ObjectSection};
+use rayon::prelude::*;
 use tracing::debug;
 
 use crate::function::{
     FormalParameter, FormalParameterKind, Function, MemoryParam, ParamFindingFailure, Register,
 };
+use crate::lines::{InlinedRange, LineTable};
 use ddbug_parser::FileHash;
 
-pub fn get_functions_dwarf(filename: &str, obj: &object::File) -> crate::defs::Result<Vec<Function>> {
+pub fn get_functions_dwarf(
+    filename: &str,
+    obj: &object::File,
+) -> crate::defs::Result<Vec<Function>> {
     let line_bp = dwarf_get_line_breakpoints(obj)?;
     let mut funcs = Vec::new();
     ddbug_parser::File::parse(filename, |file| {
         let file_hash = FileHash::new(file);
-        for unit in file.units() {
-            for function in unit.functions() {
-                if let Some(name) = function.name() {
-                    let details = function.details(&file_hash);
-                    let params = details
-                        .parameters()
-                        .into_iter()
-                        .map(|p| parse_dwarf_param(p, &file_hash))
-                        .collect::<Vec<_>>();
-                    if let Some(address) = function.address() {
-                        funcs.push(Function {
-                            name: name.to_string(),
-                            parameters: params,
-                            address,
-                            prologue_end_addr: line_bp.get(&address).map(|x| *x),
-                            return_type: None,
-                        })
-                    }
-                }
-            }
-        }
+        funcs = file
+            .units()
+            .par_iter()
+            .flat_map_iter(|unit| unit.functions())
+            .filter_map(|function| {
+                let name = function.name()?;
+                let address = function.address()?;
+                let details = function.details(&file_hash);
+                let params = details
+                    .parameters()
+                    .iter()
+                    .map(|p| parse_dwarf_param(p, &file_hash))
+                    .collect::<Vec<_>>();
+                Some(Function {
+                    name: name.to_string(),
+                    parameters: params,
+                    address,
+                    prologue_end_addr: line_bp.get(&address).copied(),
+                    return_type: parse_dwarf_return_type(function, &file_hash),
+                    inlined: false,
+                    exit_addrs: vec![],
+                    decl_file: None,
+                    decl_line: None,
+                    unit: None,
+                })
+            })
+            .collect();
         Ok(())
     })?;
 
@@ -72,13 +83,37 @@ fn parse_dwarf_param(
     Err(ParamFindingFailure::DwarfNoFrameLocNoReg)
 }
 
+/// Returns `None` for functions without a `DW_AT_type`, i.e. returning void
+fn parse_dwarf_return_type(
+    function: &ddbug_parser::Function,
+    file: &ddbug_parser::FileHash,
+) -> Option<FormalParameter> {
+    let ty = ddbug_type_to_type(&Some(function.return_type(file)?.into_owned()));
+    let register = match ty {
+        Some(TypeKind::Void) => return None,
+        Some(TypeKind::BaseType(BaseType {
+            encoding: BaseTypeEncoding::Float,
+            ..
+        })) => gimli::X86_64::XMM0,
+        _ => gimli::X86_64::RAX,
+    };
+    Some(FormalParameter {
+        name: None,
+        kind: FormalParameterKind::Register(register),
+        ty,
+    })
+}
+
 fn ddbug_type_to_type(ty: &Option<ddbug_parser::Type>) -> Option<TypeKind> {
     if let Some(ty) = ty {
         return match ty.kind() {
             ddbug_parser::TypeKind::Void => Some(TypeKind::Void),
             ddbug_parser::TypeKind::Base(b) => Some(TypeKind::BaseType(BaseType {
                 size: b.byte_size().unwrap(),
-                encoding: super::BaseTypeEncoding::Unsigned,
+                encoding: match b.encoding() {
+                    ddbug_parser::BaseTypeEncoding::Float => BaseTypeEncoding::Float,
+                    _ => BaseTypeEncoding::Unsigned,
+                },
             })),
             _ => None,
         };
@@ -88,12 +123,15 @@ fn ddbug_type_to_type(ty: &Option<ddbug_parser::Type>) -> Option<TypeKind> {
 
 use std::{borrow, collections::HashMap};
 
-use super::{BaseType, TypeKind};
+use super::{BaseType, BaseTypeEncoding, TypeKind};
 
-// TODO: This entire function is a big hack
-// beef up this func and remove ddbug dep
-// research on how to actually get to the breakpoint line info from a function DIE
-pub fn dwarf_get_line_breakpoints(obj: &object::File) -> crate::defs::Result<HashMap<u64, u64>> {
+type DwarfSlice<'a> = gimli::EndianSlice<'a, gimli::RunTimeEndian>;
+
+/// Loads the DWARF sections of `obj` and hands the parsed sections to `f`
+fn with_dwarf<T, F>(obj: &object::File, f: F) -> crate::defs::Result<T>
+where
+    F: FnOnce(&gimli::Dwarf<DwarfSlice>) -> crate::defs::Result<T>,
+{
     let load_section = |id: gimli::SectionId| -> Result<borrow::Cow<[u8]>, gimli::Error> {
         match obj.section_by_name(id.name()) {
             Some(ref section) => Ok(section
@@ -112,64 +150,515 @@ pub fn dwarf_get_line_breakpoints(obj: &object::File) -> crate::defs::Result<Has
 
     // Create `EndianSlice`s for all of the sections.
     let dwarf = dwarf_cow.borrow(&borrow_section);
+    f(&dwarf)
+}
 
+/// Runs `f` on every compilation unit in parallel, keeping the results in unit order
+fn par_units<T, F>(dwarf: &gimli::Dwarf<DwarfSlice>, f: F) -> crate::defs::Result<Vec<T>>
+where
+    T: Send,
+    F: Fn(gimli::Unit<DwarfSlice>) -> crate::defs::Result<Vec<T>> + Sync,
+{
+    let mut headers = vec![];
     let mut iter = dwarf.units();
-    let mut bp_map = HashMap::new();
     while let Some(header) = iter.next()? {
-        let unit = dwarf.unit(header)?;
-        let mut breakpoint_addrs = vec![];
+        headers.push(header);
+    }
+    let per_unit = headers
+        .into_par_iter()
+        .map(|header| f(dwarf.unit(header)?))
+        .collect::<crate::defs::Result<Vec<_>>>()?;
+    Ok(per_unit.into_iter().flatten().collect())
+}
+
+/// Finds where the prologue of every function with DWARF info ends, by the start address
+///
+/// Parameters located relative to the frame base are only valid once the prologue ran, so
+/// this is where their values get read.
+pub fn dwarf_get_line_breakpoints(obj: &object::File) -> crate::defs::Result<HashMap<u64, u64>> {
+    with_dwarf(obj, dwarf_get_line_breakpoints_inner)
+}
+
+/// A row of a line program, as needed to find the end of prologues
+#[derive(Debug, Clone, Copy)]
+struct LineRow {
+    address: u64,
+    is_stmt: bool,
+    prologue_end: bool,
+}
+
+fn dwarf_get_line_breakpoints_inner(
+    dwarf: &gimli::Dwarf<DwarfSlice>,
+) -> crate::defs::Result<HashMap<u64, u64>> {
+    let bps = par_units(dwarf, |unit| {
+        // the rows of each sequence, a function can't straddle two of them
+        let mut sequences: Vec<Vec<LineRow>> = vec![];
         if let Some(line_program) = unit.line_program.clone() {
             let mut rows = line_program.rows();
+            let mut sequence = vec![];
             while let Some((_, row)) = rows.next_row()? {
-                if row.is_stmt() {
-                    breakpoint_addrs.push(row.address());
+                sequence.push(LineRow {
+                    address: row.address(),
+                    is_stmt: row.is_stmt(),
+                    prologue_end: row.prologue_end(),
+                });
+                if row.end_sequence() {
+                    sequences.push(std::mem::take(&mut sequence));
                 }
             }
         }
-        breakpoint_addrs.sort();
-        debug!(?breakpoint_addrs);
+        sequences.sort_unstable_by_key(|rows| rows[0].address);
 
+        let mut bps = vec![];
         let mut entries = unit.entries();
         while let Some((_, entry)) = entries.next_dfs()? {
-            if entry.tag() == gimli::DW_TAG_subprogram {
-                // let frame_base_attr = entry.attr_value(gimli::DW_AT_frame_base)?;
-                // match frame_base_attr {
-                //     Some(AttributeValue::Exprloc(expr)) => {
-                //         let mut eval = expr.evaluation(unit.encoding());
-                //         let mut result = eval.evaluate()?;
-                //         println!("{:?}", result);
-                //         while result != EvaluationResult::Complete {
-                //             match result {
-                //                 EvaluationResult::RequiresCallFrameCfa => {
-                //                     result = eval.resume_with_call_frame_cfa(12341234).unwrap();
-                //                 },
-                //                 _ => unimplemented!(),
-                //             }
-                //         }
-                //         let result = eval.result();
-                //         println!("{:?}", result);
-                //     }
-                //     _ => {}
-                // };
-                match (
-                    entry.attr_value(DW_AT_low_pc)?,
-                    entry.attr_value(DW_AT_high_pc)?,
-                ) {
-                    (Some(AttributeValue::Addr(low_pc)), Some(AttributeValue::Udata(high_pc))) => {
-                        debug!(?low_pc, ?high_pc);
-                        if let Some(bp) = breakpoint_addrs
-                            .iter()
-                            .find(|&&x| x > low_pc && x < low_pc + high_pc)
-                        {
-                            bp_map.insert(low_pc, *bp);
+            if entry.tag() != gimli::DW_TAG_subprogram {
+                continue;
+            }
+            let mut ranges = vec![];
+            let mut range_iter = dwarf.die_ranges(&unit, entry)?;
+            while let Some(range) = range_iter.next()? {
+                if range.begin < range.end {
+                    ranges.push(range);
+                }
+            }
+            let start = match dwarf_attr_address(dwarf, &unit, entry, DW_AT_low_pc)? {
+                Some(start) => Some(start),
+                None => dwarf_attr_address(dwarf, &unit, entry, gimli::DW_AT_entry_pc)?,
+            };
+            let start = match start.or_else(|| ranges.first().map(|range| range.begin)) {
+                Some(start) => start,
+                None => continue,
+            };
+            // the prologue is in the range the function starts in
+            let end = match ranges.iter().find(|r| r.begin <= start && start < r.end) {
+                Some(range) => range.end,
+                None => continue,
+            };
+            let sequence = sequence_at(&sequences, start);
+            if let Some(bp) = sequence.and_then(|rows| prologue_end(rows, start, end)) {
+                debug!(?start, ?bp, "prologue end");
+                bps.push((start, bp));
+            }
+        }
+        Ok(bps)
+    })?;
+    Ok(bps.into_iter().collect())
+}
+
+/// The rows of the sequence containing `address`, from sequences sorted by their start
+fn sequence_at(sequences: &[Vec<LineRow>], address: u64) -> Option<&[LineRow]> {
+    let index = sequences.partition_point(|rows| rows[0].address <= address);
+    let rows = sequences.get(index.checked_sub(1)?)?;
+    // the last row ends the sequence
+    (address < rows.last()?.address).then_some(rows.as_slice())
+}
+
+/// The end of the prologue of the function at `start`: the first row flagged `prologue_end`,
+/// or else the second statement of the function, as compilers start a new row for the body
+fn prologue_end(rows: &[LineRow], start: u64, end: u64) -> Option<u64> {
+    let first = rows.partition_point(|row| row.address < start);
+    let function = rows[first..].iter().take_while(|row| row.address < end);
+    let mut statements = function.clone().filter(|row| row.is_stmt);
+    let bp = match function.clone().find(|row| row.prologue_end) {
+        Some(row) => row.address,
+        None => statements.find(|row| row.address > start)?.address,
+    };
+    // a function without a prologue is traced from its start
+    if bp > start {
+        Some(bp)
+    } else {
+        None
+    }
+}
+
+/// Collects every `DW_TAG_inlined_subroutine` instance as a [`Function`]
+///
+/// The entry of an instance is its `DW_AT_entry_pc` (or the start of its first range) and
+/// the ends of its ranges are used as best-effort exit addresses.
+pub fn get_inlined_functions_dwarf(obj: &object::File) -> crate::defs::Result<Vec<Function>> {
+    with_dwarf(obj, |dwarf| {
+        par_units(dwarf, |unit| {
+            let comp_dir = unit.comp_dir.map(|dir| dir.to_string_lossy().into_owned());
+            let mut funcs = Vec::new();
+            let mut entries = unit.entries();
+            while let Some((_, entry)) = entries.next_dfs()? {
+                if entry.tag() != gimli::DW_TAG_inlined_subroutine {
+                    continue;
+                }
+                let name = match dwarf_die_name(dwarf, &unit, entry)? {
+                    Some(name) => name,
+                    None => continue,
+                };
+
+                let mut ranges = vec![];
+                let mut range_iter = dwarf.die_ranges(&unit, entry)?;
+                while let Some(range) = range_iter.next()? {
+                    if range.begin < range.end {
+                        ranges.push(range);
+                    }
+                }
+                if ranges.is_empty() {
+                    continue;
+                }
+                ranges.sort_by_key(|r| r.begin);
+
+                let address = dwarf_attr_address(dwarf, &unit, entry, gimli::DW_AT_entry_pc)?
+                    .unwrap_or(ranges[0].begin);
+                let (decl_file, decl_line) =
+                    dwarf_decl_location(dwarf, &unit, entry, comp_dir.as_deref())?;
+                debug!(?name, ?address, ?ranges, "found inlined subroutine");
+                funcs.push(Function {
+                    address,
+                    prologue_end_addr: None,
+                    name,
+                    parameters: vec![],
+                    return_type: None,
+                    inlined: true,
+                    exit_addrs: ranges.iter().map(|r| r.end).collect(),
+                    decl_file,
+                    decl_line,
+                    unit: None,
+                });
+            }
+            Ok(funcs)
+        })
+    })
+}
+
+/// Fills the declaration file and compile unit of the functions, from the subprograms of
+/// the DWARF info which start at their address
+pub fn annotate_sources_dwarf(
+    obj: &object::File,
+    funcs: &mut [Function],
+) -> crate::defs::Result<()> {
+    type Source = (Option<String>, Option<u64>, Option<String>);
+    let sources: HashMap<u64, Source> = with_dwarf(obj, |dwarf| {
+        par_units(dwarf, |unit| {
+            let unit_name = unit.name.map(|name| name.to_string_lossy().into_owned());
+            let comp_dir = unit.comp_dir.map(|dir| dir.to_string_lossy().into_owned());
+            let mut sources = vec![];
+            let mut entries = unit.entries();
+            while let Some((_, entry)) = entries.next_dfs()? {
+                if entry.tag() != gimli::DW_TAG_subprogram {
+                    continue;
+                }
+                let low_pc = match dwarf_attr_address(dwarf, &unit, entry, DW_AT_low_pc)? {
+                    Some(low_pc) => low_pc,
+                    // a function split in several ranges starts in the first one
+                    None => match dwarf.die_ranges(&unit, entry)?.next()? {
+                        Some(range) => range.begin,
+                        None => continue,
+                    },
+                };
+                let (decl_file, decl_line) =
+                    dwarf_decl_location(dwarf, &unit, entry, comp_dir.as_deref())?;
+                sources.push((low_pc, (decl_file, decl_line, unit_name.clone())));
+            }
+            Ok(sources)
+        })
+    })?
+    .into_iter()
+    .collect();
+
+    for func in funcs.iter_mut().filter(|func| !func.inlined) {
+        if let Some((decl_file, decl_line, unit)) = sources.get(&func.address) {
+            func.decl_file = func.decl_file.take().or_else(|| decl_file.clone());
+            func.decl_line = func.decl_line.or(*decl_line);
+            func.unit = func.unit.take().or_else(|| unit.clone());
+        }
+    }
+    Ok(())
+}
+
+/// Reads an address attribute of a DIE, DWARF 5 can give it as an index into `.debug_addr`
+fn dwarf_attr_address(
+    dwarf: &gimli::Dwarf<DwarfSlice>,
+    unit: &gimli::Unit<DwarfSlice>,
+    entry: &gimli::DebuggingInformationEntry<DwarfSlice>,
+    attr: gimli::DwAt,
+) -> crate::defs::Result<Option<u64>> {
+    match entry.attr_value(attr)? {
+        Some(value) => Ok(dwarf.attr_address(unit, value)?),
+        None => Ok(None),
+    }
+}
+
+/// Finds where the function of a DIE is declared, following its abstract origin or
+/// specification
+fn dwarf_decl_location(
+    dwarf: &gimli::Dwarf<DwarfSlice>,
+    unit: &gimli::Unit<DwarfSlice>,
+    entry: &gimli::DebuggingInformationEntry<DwarfSlice>,
+    comp_dir: Option<&str>,
+) -> crate::defs::Result<(Option<String>, Option<u64>)> {
+    if let Some(AttributeValue::FileIndex(index)) = entry.attr_value(gimli::DW_AT_decl_file)? {
+        let file = dwarf_file_path(dwarf, unit, index, comp_dir)?;
+        let line = entry
+            .attr_value(gimli::DW_AT_decl_line)?
+            .and_then(|line| line.udata_value());
+        return Ok((file, line));
+    }
+    for attr in [gimli::DW_AT_abstract_origin, gimli::DW_AT_specification] {
+        if let Some(AttributeValue::UnitRef(offset)) = entry.attr_value(attr)? {
+            let origin = unit.entry(offset)?;
+            return dwarf_decl_location(dwarf, unit, &origin, comp_dir);
+        }
+    }
+    Ok((None, None))
+}
+
+/// Reads the rows of every line program and the inlined calls into a [`LineTable`]
+pub fn dwarf_line_table(obj: &object::File) -> crate::defs::Result<LineTable> {
+    let units = with_dwarf(obj, |dwarf| {
+        par_units(dwarf, |unit| {
+            let program = match unit.line_program.clone() {
+                Some(program) => program,
+                None => return Ok(vec![]),
+            };
+            let comp_dir = unit.comp_dir.map(|dir| dir.to_string_lossy().into_owned());
+            // the file indexes of the unit, in the order they are first used
+            let mut indexes: HashMap<u64, usize> = HashMap::new();
+            let mut files = vec![];
+            let mut file = |index: u64| -> crate::defs::Result<usize> {
+                if let Some(&file) = indexes.get(&index) {
+                    return Ok(file);
+                }
+                let path = dwarf_file_path(dwarf, &unit, index, comp_dir.as_deref())?;
+                files.push(path.unwrap_or_else(|| "??".to_owned()));
+                indexes.insert(index, files.len() - 1);
+                Ok(files.len() - 1)
+            };
+
+            let mut rows = vec![];
+            let mut program_rows = program.rows();
+            while let Some((_, row)) = program_rows.next_row()? {
+                if row.end_sequence() {
+                    rows.push((row.address(), None));
+                    continue;
+                }
+                let line = row.line().map_or(0, |line| line.get());
+                rows.push((row.address(), Some((file(row.file_index())?, line))));
+            }
+
+            let mut inlined = vec![];
+            let mut depth = 0;
+            let mut entries = unit.entries();
+            while let Some((delta, entry)) = entries.next_dfs()? {
+                depth = (depth as isize + delta) as usize;
+                if entry.tag() != gimli::DW_TAG_inlined_subroutine {
+                    continue;
+                }
+                let call_file = match entry.attr_value(gimli::DW_AT_call_file)? {
+                    Some(AttributeValue::FileIndex(index)) => file(index)?,
+                    _ => continue,
+                };
+                let call_line = entry
+                    .attr_value(gimli::DW_AT_call_line)?
+                    .and_then(|line| line.udata_value())
+                    .unwrap_or(0);
+                let function =
+                    dwarf_die_name(dwarf, &unit, entry)?.unwrap_or_else(|| "??".to_owned());
+                let mut ranges = vec![];
+                let mut range_iter = dwarf.die_ranges(&unit, entry)?;
+                while let Some(range) = range_iter.next()? {
+                    if range.begin < range.end {
+                        ranges.push(range);
+                    }
+                }
+                let entry_pc = dwarf_attr_address(dwarf, &unit, entry, gimli::DW_AT_entry_pc)?
+                    .or_else(|| ranges.iter().map(|r| r.begin).min());
+                let entry_pc = match entry_pc {
+                    Some(entry_pc) => entry_pc,
+                    None => continue,
+                };
+                for range in ranges {
+                    inlined.push(InlinedRange {
+                        begin: range.begin,
+                        end: range.end,
+                        entry: entry_pc,
+                        depth,
+                        function: function.clone(),
+                        call: (call_file, call_line),
+                    });
+                }
+            }
+            Ok(vec![(files, rows, inlined)])
+        })
+    })?;
+    Ok(LineTable::new(units))
+}
+
+/// Resolves a file index of the line program into a path
+fn dwarf_file_path(
+    dwarf: &gimli::Dwarf<DwarfSlice>,
+    unit: &gimli::Unit<DwarfSlice>,
+    index: u64,
+    comp_dir: Option<&str>,
+) -> crate::defs::Result<Option<String>> {
+    let header = match &unit.line_program {
+        Some(program) => program.header(),
+        None => return Ok(None),
+    };
+    let file = match header.file(index) {
+        Some(file) => file,
+        None => return Ok(None),
+    };
+    let mut path = std::path::PathBuf::new();
+    if let Some(dir) = comp_dir {
+        path.push(dir);
+    }
+    if let Some(dir) = file.directory(header) {
+        // pushing an absolute path replaces what is there
+        path.push(&*dwarf.attr_string(unit, dir)?.to_string_lossy());
+    }
+    path.push(&*dwarf.attr_string(unit, file.path_name())?.to_string_lossy());
+    Ok(Some(path.to_string_lossy().into_owned()))
+}
+
+/// Gets the name of a DIE, following `DW_AT_abstract_origin` and `DW_AT_specification`
+fn dwarf_die_name(
+    dwarf: &gimli::Dwarf<DwarfSlice>,
+    unit: &gimli::Unit<DwarfSlice>,
+    entry: &gimli::DebuggingInformationEntry<DwarfSlice>,
+) -> crate::defs::Result<Option<String>> {
+    for attr in [
+        gimli::DW_AT_linkage_name,
+        gimli::DW_AT_MIPS_linkage_name,
+        gimli::DW_AT_name,
+    ] {
+        if let Some(value) = entry.attr_value(attr)? {
+            let name = dwarf.attr_string(unit, value)?;
+            return Ok(Some(name.to_string_lossy().into_owned()));
+        }
+    }
+    for attr in [gimli::DW_AT_abstract_origin, gimli::DW_AT_specification] {
+        // TODO: DebugInfoRef points into another unit, handle that
+        if let Some(AttributeValue::UnitRef(offset)) = entry.attr_value(attr)? {
+            let origin = unit.entry(offset)?;
+            return dwarf_die_name(dwarf, unit, &origin);
+        }
+    }
+    Ok(None)
+}
+
+/// Finds the address and size of a global variable, `path` can name one of its fields with
+/// `variable.field.subfield`
+pub fn get_variable_dwarf(
+    obj: &object::File,
+    path: &str,
+) -> crate::defs::Result<Option<(u64, Option<u64>)>> {
+    let mut parts = path.split('.');
+    let name = parts.next().unwrap_or_default();
+    let fields: Vec<&str> = parts.collect();
+    with_dwarf(obj, |dwarf| {
+        let found = par_units(dwarf, |unit| {
+            let mut entries = unit.entries();
+            while let Some((_, entry)) = entries.next_dfs()? {
+                if entry.tag() != gimli::DW_TAG_variable
+                    || dwarf_attr_name(dwarf, &unit, entry)?.as_deref() != Some(name)
+                {
+                    continue;
+                }
+                // only variables with a fixed address can be watched
+                let mut address = match entry.attr_value(gimli::DW_AT_location)? {
+                    Some(AttributeValue::Exprloc(expr)) => {
+                        match expr.operations(unit.encoding()).next()? {
+                            Some(gimli::Operation::Address { address }) => address,
+                            _ => continue,
                         }
                     }
-                    (_, _) => {}
+                    _ => continue,
+                };
+                let mut ty = dwarf_type_of(&unit, entry)?;
+                for field in fields.iter() {
+                    match dwarf_member(dwarf, &unit, ty, field)? {
+                        Some((offset, member_ty)) => {
+                            address += offset;
+                            ty = member_ty;
+                        }
+                        None => return Ok(vec![]),
+                    }
                 }
+                let size = match ty {
+                    Some(ty) => unit.entry(ty)?.attr_value(gimli::DW_AT_byte_size)?,
+                    None => None,
+                };
+                debug!(?path, ?address, ?size, "found variable");
+                return Ok(vec![(address, size.and_then(|size| size.udata_value()))]);
             }
+            Ok(vec![])
+        })?;
+        Ok(found.into_iter().next())
+    })
+}
+
+fn dwarf_attr_name(
+    dwarf: &gimli::Dwarf<DwarfSlice>,
+    unit: &gimli::Unit<DwarfSlice>,
+    entry: &gimli::DebuggingInformationEntry<DwarfSlice>,
+) -> crate::defs::Result<Option<String>> {
+    match entry.attr_value(gimli::DW_AT_name)? {
+        Some(value) => Ok(Some(
+            dwarf
+                .attr_string(unit, value)?
+                .to_string_lossy()
+                .into_owned(),
+        )),
+        None => Ok(None),
+    }
+}
+
+/// The type of a DIE, without the typedefs and qualifiers around it
+fn dwarf_type_of(
+    unit: &gimli::Unit<DwarfSlice>,
+    entry: &gimli::DebuggingInformationEntry<DwarfSlice>,
+) -> crate::defs::Result<Option<gimli::UnitOffset>> {
+    let mut offset = match entry.attr_value(gimli::DW_AT_type)? {
+        Some(AttributeValue::UnitRef(offset)) => offset,
+        _ => return Ok(None),
+    };
+    loop {
+        let ty = unit.entry(offset)?;
+        match ty.tag() {
+            gimli::DW_TAG_typedef | gimli::DW_TAG_const_type | gimli::DW_TAG_volatile_type => {
+                match ty.attr_value(gimli::DW_AT_type)? {
+                    Some(AttributeValue::UnitRef(next)) => offset = next,
+                    _ => return Ok(None),
+                }
+            }
+            _ => return Ok(Some(offset)),
         }
     }
-    Ok(bp_map)
+}
+
+/// Finds the offset and type of the member `name` of the struct or union `ty`
+fn dwarf_member(
+    dwarf: &gimli::Dwarf<DwarfSlice>,
+    unit: &gimli::Unit<DwarfSlice>,
+    ty: Option<gimli::UnitOffset>,
+    name: &str,
+) -> crate::defs::Result<Option<(u64, Option<gimli::UnitOffset>)>> {
+    let ty = match ty {
+        Some(ty) => ty,
+        None => return Ok(None),
+    };
+    let mut tree = unit.entries_tree(Some(ty))?;
+    let mut children = tree.root()?.children();
+    while let Some(child) = children.next()? {
+        let member = child.entry();
+        if member.tag() != gimli::DW_TAG_member
+            || dwarf_attr_name(dwarf, unit, member)?.as_deref() != Some(name)
+        {
+            continue;
+        }
+        let offset = member
+            .attr_value(gimli::DW_AT_data_member_location)?
+            .and_then(|offset| offset.udata_value())
+            .unwrap_or(0);
+        return Ok(Some((offset, dwarf_type_of(unit, member)?)));
+    }
+    Ok(None)
 }
 
 fn dwarf_parse_function(
@@ -188,3 +677,97 @@ fn dwarf_parse_function(
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn row(address: u64, is_stmt: bool, prologue_end: bool) -> LineRow {
+        LineRow {
+            address,
+            is_stmt,
+            prologue_end,
+        }
+    }
+
+    /// Statement rows at each address, the last one ending the sequence
+    fn sequence(addresses: &[u64]) -> Vec<LineRow> {
+        addresses
+            .iter()
+            .map(|&address| row(address, true, false))
+            .collect()
+    }
+
+    #[test]
+    fn prologue_ends_at_the_flagged_row() {
+        let rows = [
+            row(0x1000, true, false),
+            row(0x1004, true, false),
+            row(0x1008, false, true),
+            row(0x1010, true, false),
+            row(0x1020, true, false),
+        ];
+        assert_eq!(prologue_end(&rows, 0x1000, 0x1020), Some(0x1008));
+        // the flag of the next function doesn't count
+        assert_eq!(prologue_end(&rows, 0x1000, 0x1008), Some(0x1004));
+    }
+
+    #[test]
+    fn prologue_ends_at_the_second_statement() {
+        let rows = [
+            row(0x1000, true, false),
+            row(0x1000, true, false),
+            row(0x1004, false, false),
+            row(0x100c, true, false),
+            row(0x1020, true, false),
+        ];
+        assert_eq!(prologue_end(&rows, 0x1000, 0x1020), Some(0x100c));
+    }
+
+    #[test]
+    fn functions_without_a_usable_row_have_no_prologue() {
+        // a single statement, and rows of the next function only
+        let rows = [
+            row(0x1000, true, false),
+            row(0x1004, false, false),
+            row(0x1010, true, false),
+        ];
+        assert_eq!(prologue_end(&rows, 0x1000, 0x1010), None);
+        // the flag right at the start
+        let rows = [row(0x1000, true, true), row(0x1010, true, false)];
+        assert_eq!(prologue_end(&rows, 0x1000, 0x1010), None);
+        assert_eq!(prologue_end(&[], 0x1000, 0x1010), None);
+    }
+
+    #[test]
+    fn finds_the_sequence_a_function_starts_in() {
+        let mut sequences = vec![
+            sequence(&[0x3000, 0x3008, 0x3010]),
+            sequence(&[0x1000, 0x1008, 0x1010]),
+            sequence(&[0x2000, 0x2010]),
+        ];
+        sequences.sort_unstable_by_key(|rows| rows[0].address);
+        let start = |address| sequence_at(&sequences, address).map(|rows| rows[0].address);
+        assert_eq!(start(0xfff), None);
+        assert_eq!(start(0x1000), Some(0x1000));
+        assert_eq!(start(0x100f), Some(0x1000));
+        // between two sequences
+        assert_eq!(start(0x1010), None);
+        assert_eq!(start(0x2008), Some(0x2000));
+        assert_eq!(start(0x3000), Some(0x3000));
+        assert_eq!(start(0x3010), None);
+    }
+
+    #[test]
+    fn functions_split_across_sequences_use_the_one_they_start_in() {
+        // the hot part of the function, then its cold part in another sequence
+        let sequences = [
+            sequence(&[0x1000, 0x1008, 0x1010]),
+            sequence(&[0x5000, 0x5004, 0x5010]),
+        ];
+        let rows = sequence_at(&sequences, 0x1000).unwrap();
+        assert_eq!(prologue_end(rows, 0x1000, 0x1010), Some(0x1008));
+        let rows = sequence_at(&sequences, 0x5000).unwrap();
+        assert_eq!(prologue_end(rows, 0x5000, 0x5010), Some(0x5004));
+    }
+}
@@ -0,0 +1,39 @@
+use gimli::UnwindSection;
+use object::{Object, ObjectSection};
+use tracing::debug;
+
+/// Returns the `(start, size)` of every function described by an FDE in `.eh_frame`
+///
+/// This is the only function boundary info left in most stripped binaries.
+pub fn get_fde_ranges(obj: &object::File) -> crate::defs::Result<Vec<(u64, u64)>> {
+    let eh_frame_section = match obj.section_by_name(".eh_frame") {
+        Some(section) => section,
+        None => return Ok(vec![]),
+    };
+    let data = eh_frame_section.data()?;
+    let eh_frame = gimli::EhFrame::new(data, gimli::RunTimeEndian::Little);
+
+    let mut bases = gimli::BaseAddresses::default().set_eh_frame(eh_frame_section.address());
+    if let Some(text) = obj.section_by_name(".text") {
+        bases = bases.set_text(text.address());
+    }
+    if let Some(got) = obj.section_by_name(".got") {
+        bases = bases.set_got(got.address());
+    }
+
+    let mut ranges = vec![];
+    let mut entries = eh_frame.entries(&bases);
+    while let Some(entry) = entries.next()? {
+        if let gimli::CieOrFde::Fde(partial) = entry {
+            let fde =
+                partial.parse(|section, bases, offset| section.cie_from_offset(bases, offset))?;
+            if fde.len() > 0 {
+                ranges.push((fde.initial_address(), fde.len()));
+            }
+        }
+    }
+    ranges.sort_unstable();
+    ranges.dedup_by_key(|(start, _)| *start);
+    debug!(n_fdes = ranges.len());
+    Ok(ranges)
+}
@@ -1,112 +1,155 @@
-use std::collections::HashSet;
-use std::result;
+use std::collections::HashMap;
 
 use object::{read::ObjectSymbol, read::SymbolSection, Object, ObjectSection, SymbolKind};
+use rayon::prelude::*;
 use tracing::debug;
 
-use crate::defs::Register;
-use crate::error::ParamFindingFailure;
-
-use crate::function::{FormalParameter, FormalParameterKind, Function};
+use crate::function::analysis::{FunctionAnalysis, ReturnKind};
+use crate::function::{
+    BaseType, BaseTypeEncoding, FormalParameter, FormalParameterKind, Function, TypeKind,
+};
 
 pub fn get_functions<'a>(obj: &'a object::File) -> Vec<Function> {
-    let text_section_idx = obj.section_by_name(".text").unwrap().index();
-    let mut funcs = vec![];
-    for symbol in obj.symbols() {
+    functions_from_symbols(obj, obj.symbols())
+}
+
+/// Same as [`get_functions`] but reads the functions exported in `.dynsym`
+pub fn get_dynamic_functions(obj: &object::File) -> Vec<Function> {
+    functions_from_symbols(obj, obj.dynamic_symbols())
+}
+
+fn functions_from_symbols<'data, 'file>(
+    obj: &'file object::File<'data>,
+    symbols: object::read::SymbolIterator<'data, 'file>,
+) -> Vec<Function> {
+    let text_section_idx = match obj.section_by_name(".text") {
+        Some(text) => text.index(),
+        None => return vec![],
+    };
+    let mut ranges = vec![];
+    for symbol in symbols {
         if matches!(symbol.kind(), SymbolKind::Text) {
             match symbol.section() {
                 SymbolSection::Section(idx) if idx == text_section_idx => {
                     let func_name = symbol.name().unwrap();
-                    debug!(?func_name, "parsing fn params");
-                    let params = get_fn_params_heuristic(obj, &symbol).unwrap();
-                    funcs.push(Function {
-                        address: symbol.address(),
-                        prologue_end_addr: None,
-                        // TODO: fix this
-                        name: func_name.into(),
-                        parameters: params,
-                        return_type: Some(FormalParameterKind::Register(gimli::X86_64::RAX))
-                    });
+                    // TODO: fix this
+                    ranges.push((symbol.address(), symbol.size(), func_name.to_string()));
                 }
                 _ => {}
             }
         }
     }
-    funcs
+    functions_from_ranges(obj, ranges)
 }
 
-fn get_fn_params_heuristic<'a, T>(
-    obj: &'a object::File,
-    symbol: &T,
-) -> crate::defs::Result<Vec<result::Result<FormalParameter, ParamFindingFailure>>>
-where
-    T: object::read::ObjectSymbol<'a>,
-{
-    use capstone::prelude::*;
-
-    let code = obj
-        .section_by_index(symbol.section_index().unwrap())
-        .unwrap()
-        .data_range(symbol.address(), symbol.size())
-        .unwrap()
-        .unwrap();
-
-    let cs = Capstone::new()
-        .x86()
-        .mode(arch::x86::ArchMode::Mode64)
-        .syntax(arch::x86::ArchSyntax::Intel)
-        .detail(true)
-        .build()
-        .expect("Failed to create Capstone object");
-
-    let insns = cs.disasm_all(code, 0x1000).expect("Failed to disassemble");
-
-    let mut regs_written: HashSet<RegId> = HashSet::new();
-    regs_written.insert(capstone::arch::x86::X86Reg::X86_REG_RSP.into());
-    regs_written.insert(capstone::arch::x86::X86Reg::X86_REG_RIP.into());
-    regs_written.insert(capstone::arch::x86::X86Reg::X86_REG_RBP.into());
-    let mut arg_regs = HashSet::new();
-
-    for instr in insns.iter() {
-        let detail: InsnDetail = cs.insn_detail(&instr).expect("Failed to get insn detail");
+/// Analyses the code of each `(address, size, name)` and builds its [`Function`]
+///
+/// All the functions are analysed together, as the return type of a function depends on
+/// whether its callers use the return value and its parameters on the functions it tail calls. The analyses run on the rayon thread pool.
+pub(super) fn functions_from_ranges(
+    obj: &object::File,
+    ranges: Vec<(u64, u64, String)>,
+) -> Vec<Function> {
+    // capstone handles can't be shared, every worker gets its own
+    let mut analyses: Vec<_> = ranges
+        .par_iter()
+        .map_init(
+            || new_capstone(true),
+            |cs, (address, size, name)| {
+                debug!(?name, "analysing function");
+                function_code(obj, *address, *size)
+                    .map(|code| FunctionAnalysis::new(cs, code, *address))
+            },
+        )
+        .collect();
 
-        for reg in detail.regs_read() {
-            if let None = regs_written.get(reg) {
-                arg_regs.insert(reg.to_owned());
-            }
-        }
-        for reg in detail.regs_write() {
-            regs_written.insert(reg.to_owned());
-        }
+    // a tail call reads the arguments of the function it jumps to
+    let argument_uses: HashMap<u64, u32> = ranges
+        .iter()
+        .zip(analyses.iter())
+        .filter_map(|((address, ..), analysis)| {
+            Some((*address, analysis.as_ref()?.argument_uses()))
+        })
+        .collect();
+    for analysis in analyses.iter_mut().flatten() {
+        analysis.resolve_tail_calls(&|target| argument_uses.get(&target).copied());
     }
 
-    fn cs_reg_to_register(reg: &RegId) -> Option<Register> {
-        // TODO: fix this function
-        match reg.0 as u32 {
-            arch::x86::X86Reg::X86_REG_RDI | arch::x86::X86Reg::X86_REG_EDI => {
-                Some(gimli::X86_64::RDI)
-            }
-            arch::x86::X86Reg::X86_REG_RSI => Some(gimli::X86_64::RSI),
-            arch::x86::X86Reg::X86_REG_RDX => Some(gimli::X86_64::RDX),
-            arch::x86::X86Reg::X86_REG_RCX => Some(gimli::X86_64::RCX),
-            _ => None,
-        }
+    // (rax used, xmm0 used) by any caller, for every function which is called directly
+    let mut ret_used: HashMap<u64, (bool, bool)> = HashMap::new();
+    for site in analyses.iter().flatten().flat_map(|a| a.call_sites()) {
+        let used = ret_used.entry(site.target).or_default();
+        used.0 |= site.rax_used;
+        used.1 |= site.xmm0_used;
     }
 
-    let args: Vec<_> = arg_regs
-        .iter()
-        .filter_map(|reg| {
-            if let Some(reg) = cs_reg_to_register(reg) {
-                return Some(Ok(FormalParameter {
-                    name: None,
-                    kind: FormalParameterKind::Register(reg),
-                    ty: None,
-                }));
+    ranges
+        .into_iter()
+        .zip(analyses)
+        .map(|((address, _, name), analysis)| {
+            let (parameters, return_type) = match analysis {
+                Some(analysis) => (
+                    analysis.parameters().into_iter().map(Ok).collect(),
+                    return_type(analysis.return_kind(), ret_used.get(&address).copied()),
+                ),
+                None => (vec![], None),
+            };
+            debug!(?name, ?parameters, ?return_type);
+            Function {
+                address,
+                prologue_end_addr: None,
+                name,
+                parameters,
+                return_type,
+                inlined: false,
+                exit_addrs: vec![],
+                decl_file: None,
+                decl_line: None,
+                unit: None,
             }
-            None
         })
-        .collect();
-    debug!(?args);
+        .collect()
+}
+
+/// Decides the return value from what the function does and what its callers use
+fn return_type(kind: ReturnKind, used: Option<(bool, bool)>) -> Option<FormalParameter> {
+    let (register, size, encoding) = match (kind, used) {
+        // every caller ignores whatever is left in the registers
+        (_, Some((false, false))) | (ReturnKind::Void, _) => return None,
+        (ReturnKind::Int(size), _) => (gimli::X86_64::RAX, size, BaseTypeEncoding::Unsigned),
+        (ReturnKind::Float(size), _) => (gimli::X86_64::XMM0, size, BaseTypeEncoding::Float),
+        (ReturnKind::PassThrough, Some((false, true))) => {
+            (gimli::X86_64::XMM0, 8, BaseTypeEncoding::Float)
+        }
+        (ReturnKind::PassThrough, _) => (gimli::X86_64::RAX, 8, BaseTypeEncoding::Unsigned),
+    };
+    Some(FormalParameter {
+        name: None,
+        kind: FormalParameterKind::Register(register),
+        ty: Some(TypeKind::BaseType(BaseType {
+            size: size.min(8) as u64,
+            encoding,
+        })),
+    })
+}
 
-    Ok(args)
+pub(super) fn new_capstone(detail: bool) -> capstone::Capstone {
+    use capstone::prelude::*;
+
+    Capstone::new()
+        .x86()
+        .mode(arch::x86::ArchMode::Mode64)
+        .syntax(arch::x86::ArchSyntax::Intel)
+        .detail(detail)
+        .build()
+        .expect("Failed to create Capstone object")
+}
+
+/// Returns the bytes of the code at `address`, from whichever section contains it
+fn function_code<'data>(obj: &object::File<'data>, address: u64, size: u64) -> Option<&'data [u8]> {
+    obj.sections()
+        .find(|section| {
+            address >= section.address() && address < section.address() + section.size()
+        })
+        .and_then(|section| section.data_range(address, size).ok().flatten())
 }
@@ -1,22 +1,49 @@
+mod analysis;
+mod auto;
+mod discover;
 mod dwarf;
+mod eh_frame;
 mod heuristic;
 
+use serde::{Deserialize, Serialize};
+
 use crate::defs::Register;
 use crate::error::ParamFindingFailure;
 
-pub use dwarf::{get_functions_dwarf, dwarf_get_line_breakpoints};
-pub use heuristic::get_functions;
+pub use auto::get_functions_auto;
+pub use discover::{call_graph, discover_functions};
+pub use dwarf::{
+    annotate_sources_dwarf, dwarf_get_line_breakpoints, dwarf_line_table, get_functions_dwarf,
+    get_inlined_functions_dwarf, get_variable_dwarf,
+};
+pub use heuristic::{get_dynamic_functions, get_functions};
+
+/// Name given to functions which were found without a symbol
+pub fn synthetic_name(address: u64) -> String {
+    format!("sub_{:x}", address)
+}
 
-#[derive(Debug)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct Function {
     pub address: u64,
     pub prologue_end_addr: Option<u64>,
     pub name: String,
     pub parameters: Vec<std::result::Result<FormalParameter, ParamFindingFailure>>,
-    pub return_type: Option<FormalParameterKind>,
+    /// Where the return value is found, `None` if the function returns nothing
+    pub return_type: Option<FormalParameter>,
+    /// Is this an inlined instance of a function
+    pub inlined: bool,
+    /// Addresses at which an inlined instance is considered to have returned
+    pub exit_addrs: Vec<u64>,
+    /// Source file the function is declared in
+    pub decl_file: Option<String>,
+    /// Line of the declaration in `decl_file`
+    pub decl_line: Option<u64>,
+    /// Name of the compile unit the function is part of
+    pub unit: Option<String>,
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
 pub struct MemoryParam {
     /// offset from base ptr
     // TODO: can this be something other than base ptr?
@@ -25,38 +52,46 @@ pub struct MemoryParam {
     pub size: u64,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct FormalParameter {
     pub name: Option<String>,
     pub kind: FormalParameterKind,
     pub ty: Option<TypeKind>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize, Deserialize)]
 // TODO: rename FormalParameterKind to SourceKind
 pub enum FormalParameterKind {
     /// Parameter is stored in memory
     Memory(MemoryParam),
     /// Parameter is stored in registers
-    Register(Register),
+    Register(#[serde(with = "RegisterDef")] Register),
+    /// Parameter is passed on the stack, offset is from the RSP at function entry plus 8
+    Stack(MemoryParam),
     // TODO: structs passed by value will have the values in multiple regs
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize, Deserialize)]
 pub enum TypeKind {
     Void,
     BaseType(BaseType),
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct BaseType {
     pub size: u64,
     pub encoding: BaseTypeEncoding,
     // TODO: add endianess and encoding
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize, Deserialize)]
 pub enum BaseTypeEncoding {
     Address,
     Unsigned,
+    Float,
 }
+
+/// Lets serde see through gimli's register newtype
+#[derive(Serialize, Deserialize)]
+#[serde(remote = "Register")]
+struct RegisterDef(u16);
@@ -0,0 +1,324 @@
+//! Hooks called by the [`Tracer`](crate::Tracer) as the traced process runs
+//!
+//! Every hook of every handler is called, the strongest [`Action`] returned wins. The trace is
+//! printed by a handler of its own, which comes before the ones given to the tracer.
+
+use std::cell::RefCell;
+use std::io::{self, Write};
+use std::rc::Rc;
+
+use regex::Regex;
+
+use crate::backtrace::Backtracer;
+use crate::defs::{ProcessInfo, Result, Signal};
+use crate::function::Function;
+use crate::limits::Limit;
+use crate::lines::Sources;
+
+/// What to do once a hook returns, from the weakest to the strongest
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Action {
+    Continue,
+    /// Stop tracing the function, calls in progress still return normally. For a signal,
+    /// don't deliver it
+    Skip,
+    /// Remove the breakpoints and let the process run untraced
+    Detach,
+    Kill,
+}
+
+/// The state of the traced process when a hook is called
+pub struct TraceContext<'a> {
+    /// stopped while the hook runs
+    pub process: &'a dyn ProcessInfo,
+    /// nesting level of the call, from 1, counted from the outermost reported subtree when
+    /// only subtrees are reported (e.g. with --focus)
+    pub depth: usize,
+    /// names of the functions on the stack, outermost first
+    pub callers: &'a [&'a str],
+    /// where the innermost traced call returns to, if known
+    pub return_address: Option<u64>,
+    /// whether the innermost traced call was entered by a tail call, it returns for the call
+    /// below it too
+    pub tail_call: bool,
+    /// canonical frame address of the innermost traced call, which its parameters in memory
+    /// are found from, unknown for inlined instances
+    pub cfa: Option<u64>,
+}
+
+pub trait TraceHandler {
+    /// A reported function was entered, `args` are its formatted arguments
+    fn on_enter(
+        &mut self,
+        _ctx: &TraceContext,
+        _function: &Function,
+        _args: &[String],
+    ) -> Result<Action> {
+        Ok(Action::Continue)
+    }
+
+    /// A reported call returned, `ret` is `None` if it has no known return type
+    fn on_exit(
+        &mut self,
+        _ctx: &TraceContext,
+        _function: &Function,
+        _ret: Option<&str>,
+    ) -> Result<Action> {
+        Ok(Action::Continue)
+    }
+
+    /// A watched variable was written or read
+    fn on_watch(
+        &mut self,
+        _ctx: &TraceContext,
+        _name: &str,
+        _old: &str,
+        _new: &str,
+    ) -> Result<Action> {
+        Ok(Action::Continue)
+    }
+
+    /// A function hit a call limit and is no longer traced
+    fn on_limit(&mut self, _ctx: &TraceContext, _function: &Function, _limit: Limit) -> Result<()> {
+        Ok(())
+    }
+
+    /// The process got a signal, which is delivered unless [`Action::Skip`] is returned
+    fn on_signal(&mut self, _ctx: &TraceContext, _signal: Signal) -> Result<Action> {
+        Ok(Action::Continue)
+    }
+
+    /// The process exited, a process killed by a signal exits with 128 + the signal
+    fn on_exit_process(&mut self, _code: i32) -> Result<()> {
+        Ok(())
+    }
+
+    /// The trace is over, `limited` are the functions which hit a call limit with the number
+    /// of calls traced until then
+    fn on_finish(&mut self, _limited: &[(&Function, u64, Limit)]) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Something that happened in the traced process, only calls which pass the output filters
+/// are reported
+#[derive(Debug)]
+pub enum Event<'a> {
+    /// A function was entered, `depth` is its nesting level from 1
+    Enter {
+        function: &'a Function,
+        depth: usize,
+        args: &'a [String],
+        /// entered by a tail call, the call it replaced returns along with it
+        tail_call: bool,
+    },
+    /// A function returned, `value` is `None` if it has no known return type
+    Exit {
+        function: &'a Function,
+        depth: usize,
+        value: Option<&'a str>,
+    },
+    /// A function hit a call limit and is no longer traced
+    Limited {
+        function: &'a Function,
+        depth: usize,
+        limit: Limit,
+    },
+    /// A watched variable was written or read
+    Watch {
+        name: &'a str,
+        depth: usize,
+        old: &'a str,
+        new: &'a str,
+        /// names of the functions on the stack, outermost first
+        callers: &'a [&'a str],
+    },
+    /// The traced process exited
+    Exited { code: i32 },
+}
+
+/// Hands the hooks to a callback as [`Event`]s
+pub(crate) struct EventCallback(pub Box<dyn FnMut(&Event)>);
+
+impl TraceHandler for EventCallback {
+    fn on_enter(
+        &mut self,
+        ctx: &TraceContext,
+        function: &Function,
+        args: &[String],
+    ) -> Result<Action> {
+        (self.0)(&Event::Enter {
+            function,
+            depth: ctx.depth,
+            args,
+            tail_call: ctx.tail_call,
+        });
+        Ok(Action::Continue)
+    }
+
+    fn on_exit(
+        &mut self,
+        ctx: &TraceContext,
+        function: &Function,
+        ret: Option<&str>,
+    ) -> Result<Action> {
+        (self.0)(&Event::Exit {
+            function,
+            depth: ctx.depth,
+            value: ret,
+        });
+        Ok(Action::Continue)
+    }
+
+    fn on_watch(&mut self, ctx: &TraceContext, name: &str, old: &str, new: &str) -> Result<Action> {
+        (self.0)(&Event::Watch {
+            name,
+            depth: ctx.depth,
+            old,
+            new,
+            callers: ctx.callers,
+        });
+        Ok(Action::Continue)
+    }
+
+    fn on_limit(&mut self, ctx: &TraceContext, function: &Function, limit: Limit) -> Result<()> {
+        (self.0)(&Event::Limited {
+            function,
+            depth: ctx.depth,
+            limit,
+        });
+        Ok(())
+    }
+
+    fn on_exit_process(&mut self, code: i32) -> Result<()> {
+        (self.0)(&Event::Exited { code });
+        Ok(())
+    }
+}
+
+/// Where the trace is printed, shared by the printer and the `print`s of scripts so they come
+/// out in order
+#[derive(Clone)]
+pub(crate) struct SharedWriter(pub Rc<RefCell<Box<dyn Write>>>);
+
+impl Write for SharedWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.borrow_mut().flush()
+    }
+}
+
+/// Prints the trace as an indented call tree
+pub(crate) struct Printer {
+    pub out: SharedWriter,
+    /// with --backtrace, the functions whose calls get a backtrace under them
+    pub backtrace: Option<(Regex, Backtracer)>,
+    /// with --locations, finds where the calls come from
+    pub sources: Option<Sources>,
+}
+
+impl TraceHandler for Printer {
+    fn on_enter(
+        &mut self,
+        ctx: &TraceContext,
+        function: &Function,
+        args: &[String],
+    ) -> Result<Action> {
+        let indent = str::repeat("| ", ctx.depth);
+        write!(
+            self.out,
+            "{}{}({}){}{}",
+            indent,
+            function.name,
+            args.join(", "),
+            if function.inlined { " [inline]" } else { "" },
+            if ctx.tail_call { " -> tailcall" } else { "" }
+        )?;
+        let mut snippet = None;
+        if let Some(sources) = self.sources.as_mut() {
+            let call_site = sources.call_site(function, ctx.return_address);
+            let mut locations = vec![];
+            if let Some(call_site) = &call_site {
+                locations.push(format!("called at {}", call_site));
+            }
+            if let (Some(file), Some(line)) = (&function.decl_file, function.decl_line) {
+                locations.push(format!("declared at {}:{}", file, line));
+            }
+            if !locations.is_empty() {
+                write!(self.out, " [{}]", locations.join(", "))?;
+            }
+            snippet = call_site.and_then(|call_site| {
+                let line = sources.snippet(&call_site.location)?;
+                Some(format!(
+                    "{}  {} | {}",
+                    indent, call_site.location.line, line
+                ))
+            });
+        }
+        writeln!(self.out)?;
+        if let Some(snippet) = snippet {
+            writeln!(self.out, "{}", snippet)?;
+        }
+        if let Some((functions, backtracer)) = &self.backtrace {
+            if functions.is_match(&function.name) {
+                for (n, frame) in backtracer.backtrace(ctx.process)?.iter().enumerate() {
+                    writeln!(self.out, "{}  #{} {}", indent, n, frame)?;
+                }
+            }
+        }
+        Ok(Action::Continue)
+    }
+
+    fn on_exit(
+        &mut self,
+        ctx: &TraceContext,
+        _function: &Function,
+        ret: Option<&str>,
+    ) -> Result<Action> {
+        if let Some(value) = ret {
+            writeln!(self.out, "{}{}", str::repeat("| ", ctx.depth), value)?;
+        }
+        Ok(Action::Continue)
+    }
+
+    fn on_watch(&mut self, ctx: &TraceContext, name: &str, old: &str, new: &str) -> Result<Action> {
+        writeln!(
+            self.out,
+            "{}* {}: {} -> {} [{}]",
+            str::repeat("| ", ctx.depth),
+            name,
+            old,
+            new,
+            ctx.callers.join(" > ")
+        )?;
+        Ok(Action::Continue)
+    }
+
+    fn on_limit(&mut self, ctx: &TraceContext, function: &Function, limit: Limit) -> Result<()> {
+        writeln!(
+            self.out,
+            "{}[{} {}, no longer traced]",
+            str::repeat("| ", ctx.depth),
+            function.name,
+            limit
+        )?;
+        Ok(())
+    }
+
+    fn on_finish(&mut self, limited: &[(&Function, u64, Limit)]) -> Result<()> {
+        if !limited.is_empty() {
+            writeln!(self.out, "functions no longer traced:")?;
+            for (function, calls, limit) in limited {
+                writeln!(
+                    self.out,
+                    "  {}: {} calls traced, {}",
+                    function.name, calls, limit
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
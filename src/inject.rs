@@ -0,0 +1,365 @@
+//! `--inject`, forcing functions to fail by rewriting their arguments and return values
+//!
+//! An injection is `function=value` to replace the return value, or
+//! `function:setting,setting...` with the settings
+//!
+//! - `ret=<int>`: the return value
+//! - `errno=<EIO|int>`: the errno set with the return value, needs the binary to define
+//!   `errno`, so to be statically linked
+//! - `argN=<int>` or `<parameter>=<int>`: rewrites an argument at the entry
+//! - `skip`: returns right away instead of running the body, needs `ret`
+//! - `nth=<n>`: only the nth call, from 1
+//! - `prob=<p>`: each call with the probability `p`
+//!
+//! e.g. `alloc_node=0`, `read_config:ret=-1,errno=EIO,skip` or `open_log:arg1=0,prob=0.5`.
+//!
+//! Only the functions defined in the traced binary which pass the filters can be injected
+//! into, injecting into the imports of shared libraries such as `malloc` or `read` is an
+//! error as they are never traced. Libc functions can be injected into in statically linked
+//! binaries.
+
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use nix::errno::Errno;
+use object::{Object, ObjectSection, ObjectSymbol, SymbolKind};
+use tracing::warn;
+
+use crate::defs::{ProcessInfo, Result};
+use crate::error::Error;
+use crate::function::{FormalParameter, Function};
+use crate::process_ext::ProcessExt;
+use crate::utils::parse_address;
+
+#[derive(Debug, Clone)]
+pub struct Injection {
+    /// name of the function the injection applies to
+    pub function: String,
+    ret: Option<i64>,
+    errno: Option<i32>,
+    args: Vec<(Param, i64)>,
+    skip: bool,
+    nth: Option<u64>,
+    prob: Option<f64>,
+}
+
+#[derive(Debug, Clone)]
+enum Param {
+    Name(String),
+    Arg(usize),
+}
+
+/// What happens to the return of a call picked by an injection
+#[derive(Debug, Clone, Copy)]
+pub struct Fault {
+    ret: Option<i64>,
+    /// the errno and the offset of `errno` below the thread pointer
+    errno: Option<(i32, u64)>,
+    /// whether the body of the function is skipped
+    pub skip: bool,
+}
+
+/// Picks the calls to inject into
+pub struct Injector {
+    injections: Vec<Injection>,
+    /// calls seen by each injection
+    calls: Vec<u64>,
+    rng: u64,
+    /// offset of the thread local `errno` below the thread pointer
+    errno_offset: Option<u64>,
+}
+
+impl Injector {
+    /// Fails if an injection is into an import or sets errno when the binary has none
+    pub fn new(injections: Vec<Injection>, obj: &object::File) -> Result<Self> {
+        if let Some(injection) = injections.iter().find(|i| is_import(obj, &i.function)) {
+            return Err(Error::ImportedFunction(injection.function.clone()));
+        }
+        let errno_offset = errno_offset(obj);
+        if errno_offset.is_none() && injections.iter().any(|i| i.errno.is_some()) {
+            return Err(Error::NoErrno);
+        }
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |time| time.as_nanos() as u64);
+        Ok(Self {
+            calls: vec![0; injections.len()],
+            injections,
+            // xorshift gets stuck on 0
+            rng: seed | 1,
+            errno_offset,
+        })
+    }
+
+    /// Names of the functions injected into
+    pub fn functions(&self) -> impl Iterator<Item = &str> {
+        self.injections
+            .iter()
+            .map(|injection| injection.function.as_str())
+    }
+
+    /// Rewrites the arguments of a call which was just entered with the canonical frame address
+    /// `cfa`, returns what happens when it returns if the call was picked
+    pub fn enter<P: ProcessInfo>(
+        &mut self,
+        process: &mut P,
+        func: &Function,
+        cfa: u64,
+    ) -> Result<Option<Fault>> {
+        // every injection counts the call, even if an earlier one picks it
+        for (n, injection) in self.injections.iter().enumerate() {
+            if injection.function == func.name {
+                self.calls[n] += 1;
+            }
+        }
+        for (n, injection) in self.injections.iter().enumerate() {
+            if injection.function != func.name {
+                continue;
+            }
+            if matches!(injection.nth, Some(nth) if nth != self.calls[n]) {
+                continue;
+            }
+            if let Some(prob) = injection.prob {
+                // xorshift64
+                self.rng ^= self.rng << 13;
+                self.rng ^= self.rng >> 7;
+                self.rng ^= self.rng << 17;
+                if (self.rng >> 11) as f64 / (1u64 << 53) as f64 >= prob {
+                    continue;
+                }
+            }
+
+            for (param, value) in injection.args.iter() {
+                match param.find(func) {
+                    Some(formal) => process.set_param_value(formal, *value as u64, Some(cfa))?,
+                    None => warn!(?param, function = %func.name, "no such parameter"),
+                }
+            }
+            if injection.ret.is_none() {
+                return Ok(None);
+            }
+            return Ok(Some(Fault {
+                ret: injection.ret,
+                errno: injection.errno.zip(self.errno_offset),
+                skip: injection.skip,
+            }));
+        }
+        Ok(None)
+    }
+}
+
+impl Fault {
+    /// Sets the return value and errno, the call must be returning
+    pub fn apply<P: ProcessInfo>(&self, process: &mut P) -> Result<()> {
+        let mut registers = process.get_registers()?;
+        if let Some(ret) = self.ret {
+            registers.rax = ret as u64;
+            process.set_registers(registers)?;
+        }
+        if let Some((errno, offset)) = self.errno {
+            process.write_at(registers.fs_base - offset, &errno.to_le_bytes())?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns from the function which was just entered without running its body, `cfa` is the
+/// canonical frame address of the call
+pub fn return_early<P: ProcessInfo>(process: &P, cfa: u64) -> Result<()> {
+    let mut registers = process.get_registers()?;
+    // past the prologue of a function with a frame pointer, the caller's RBP was pushed
+    // right below the return address
+    if registers.rbp == cfa - 16 {
+        registers.rbp = process.read_u64_at(cfa - 16)?;
+    }
+    registers.rip = process.read_u64_at(cfa - 8)?;
+    registers.rsp = cfa;
+    process.set_registers(registers)
+}
+
+impl Param {
+    fn find<'f>(&self, func: &'f Function) -> Option<&'f FormalParameter> {
+        match self {
+            Param::Arg(n) => func.parameters.get(*n)?.as_ref().ok(),
+            Param::Name(name) => func
+                .parameters
+                .iter()
+                .flatten()
+                .find(|param| param.name.as_deref() == Some(name.as_str())),
+        }
+    }
+}
+
+/// Whether `name` is a function the binary imports from a shared library
+fn is_import(obj: &object::File, name: &str) -> bool {
+    obj.dynamic_symbols()
+        .any(|symbol| symbol.is_undefined() && symbol.name() == Ok(name))
+}
+
+/// Finds where the thread local `errno` is, for executables using the local exec TLS model
+/// where the TLS block sits right below the thread pointer
+fn errno_offset(obj: &object::File) -> Option<u64> {
+    let symbol = obj.symbols().find(|symbol| {
+        symbol.kind() == SymbolKind::Tls && matches!(symbol.name(), Ok("errno" | "__libc_errno"))
+    })?;
+    let sections: Vec<_> = [".tdata", ".tbss"]
+        .iter()
+        .filter_map(|name| obj.section_by_name(name))
+        .collect();
+    let start = sections.iter().map(|s| s.address()).min()?;
+    let end = sections.iter().map(|s| s.address() + s.size()).max()?;
+    let align = sections.iter().map(|s| s.align()).max()?.max(1);
+    let size = (end - start).div_ceil(align) * align;
+    Some(size - symbol.address())
+}
+
+fn parse_int(s: &str) -> std::result::Result<i64, String> {
+    let invalid = |_| format!("invalid number {:?}", s);
+    match s.strip_prefix('-') {
+        Some(s) if s.starts_with("0x") => parse_address(s).map(|n| -(n as i64)).map_err(invalid),
+        _ if s.starts_with("0x") => parse_address(s).map(|n| n as i64).map_err(invalid),
+        _ => s.parse().map_err(invalid),
+    }
+}
+
+fn parse_errno(s: &str) -> std::result::Result<i32, String> {
+    if let Ok(errno) = s.parse() {
+        return Ok(errno);
+    }
+    (1..256)
+        .find(|&n| format!("{:?}", Errno::from_i32(n)) == s)
+        .ok_or_else(|| format!("unknown errno {}", s))
+}
+
+impl FromStr for Injection {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let (function, settings) = match s.split_once(':') {
+            Some((function, settings)) => (function, settings.to_owned()),
+            None => {
+                let (function, ret) = s
+                    .split_once('=')
+                    .ok_or("expected <function>=<value> or <function>:<settings>")?;
+                (function, format!("ret={}", ret))
+            }
+        };
+        let mut injection = Self {
+            function: function.to_owned(),
+            ret: None,
+            errno: None,
+            args: vec![],
+            skip: false,
+            nth: None,
+            prob: None,
+        };
+        for setting in settings.split(',').map(str::trim) {
+            let (key, value) = match setting.split_once('=') {
+                Some((key, value)) => (key.trim(), value.trim()),
+                None if setting == "skip" => {
+                    injection.skip = true;
+                    continue;
+                }
+                None => return Err(format!("expected <setting>=<value>, got {:?}", setting)),
+            };
+            match key {
+                "ret" => injection.ret = Some(parse_int(value)?),
+                "errno" => injection.errno = Some(parse_errno(value)?),
+                "nth" => {
+                    injection.nth = Some(
+                        value
+                            .parse()
+                            .map_err(|_| format!("invalid nth {:?}", value))?,
+                    )
+                }
+                "prob" => {
+                    injection.prob = Some(
+                        value
+                            .parse()
+                            .map_err(|_| format!("invalid probability {:?}", value))?,
+                    )
+                }
+                _ => {
+                    let param = match key.strip_prefix("arg").map(str::parse) {
+                        Some(Ok(n)) => Param::Arg(n),
+                        _ => Param::Name(key.to_owned()),
+                    };
+                    injection.args.push((param, parse_int(value)?));
+                }
+            }
+        }
+        if injection.skip && injection.ret.is_none() {
+            return Err("skip needs a return value".to_owned());
+        }
+        if injection.errno.is_some() && injection.ret.is_none() {
+            return Err("errno needs a return value".to_owned());
+        }
+        Ok(injection)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(s: &str) -> std::result::Result<Injection, String> {
+        s.parse()
+    }
+
+    #[test]
+    fn parses_a_return_value() {
+        let injection = parse("alloc_node=0").unwrap();
+        assert_eq!(injection.function, "alloc_node");
+        assert_eq!(injection.ret, Some(0));
+        assert!(!injection.skip && injection.args.is_empty());
+    }
+
+    #[test]
+    fn parses_settings() {
+        let injection = parse("read_config:ret=-1, errno=EIO,skip,nth=3").unwrap();
+        assert_eq!(injection.function, "read_config");
+        assert_eq!(injection.ret, Some(-1));
+        assert_eq!(injection.errno, Some(Errno::EIO as i32));
+        assert!(injection.skip);
+        assert_eq!(injection.nth, Some(3));
+
+        let injection = parse("open_log:arg1=0x10,mode=-0x2,prob=0.5").unwrap();
+        assert_eq!(injection.ret, None);
+        assert_eq!(injection.prob, Some(0.5));
+        assert!(matches!(injection.args[0], (Param::Arg(1), 16)));
+        assert!(matches!(&injection.args[1], (Param::Name(name), -2) if name == "mode"));
+    }
+
+    #[test]
+    fn parses_errno_names_and_numbers() {
+        assert_eq!(parse_errno("ENOENT"), Ok(Errno::ENOENT as i32));
+        assert_eq!(parse_errno("ENOMEM"), Ok(Errno::ENOMEM as i32));
+        assert_eq!(parse_errno("28"), Ok(28));
+        assert_eq!(
+            parse_errno("EWHATEVER"),
+            Err("unknown errno EWHATEVER".to_owned())
+        );
+        assert!(parse_errno("eio").is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_injections() {
+        for (injection, error) in [
+            (
+                "alloc_node",
+                "expected <function>=<value> or <function>:<settings>",
+            ),
+            ("alloc_node=zero", "invalid number \"zero\""),
+            ("f:ret", "expected <setting>=<value>, got \"ret\""),
+            ("f:ret=1,errno=EWHATEVER", "unknown errno EWHATEVER"),
+            ("f:ret=0,nth=first", "invalid nth \"first\""),
+            ("f:ret=0,prob=half", "invalid probability \"half\""),
+            ("f:arg0=0xzz", "invalid number \"0xzz\""),
+            ("f:skip", "skip needs a return value"),
+            ("f:arg0=1,skip", "skip needs a return value"),
+            ("f:errno=EIO", "errno needs a return value"),
+        ] {
+            assert_eq!(parse(injection).unwrap_err(), error, "{}", injection);
+        }
+    }
+}
@@ -0,0 +1,122 @@
+//! Lazy breakpoint placement
+//!
+//! Instead of instrumenting every function up front, only the functions reachable from the
+//! entry point get breakpoints, and each time a function is hit its callees are instrumented.
+
+use std::collections::{HashMap, HashSet};
+
+pub struct LazyPlacer {
+    /// function address to the addresses of the functions it calls
+    call_graph: HashMap<u64, Vec<u64>>,
+    /// function address to breakpoint addresses, for the functions being traced
+    bp_addrs: HashMap<u64, Vec<u64>>,
+    visited: HashSet<u64>,
+}
+
+impl LazyPlacer {
+    pub fn new(call_graph: HashMap<u64, Vec<u64>>, bp_addrs: HashMap<u64, Vec<u64>>) -> Self {
+        Self {
+            call_graph,
+            bp_addrs,
+            visited: HashSet::new(),
+        }
+    }
+
+    /// Returns the breakpoints of the traced functions reachable from `funcs`
+    ///
+    /// Functions which are not traced are walked through, so their traced callees are found.
+    /// Every breakpoint is only returned once.
+    pub fn reachable(&mut self, funcs: &[u64]) -> Vec<u64> {
+        let mut to_visit = funcs.to_vec();
+        let mut bps = vec![];
+        while let Some(func) = to_visit.pop() {
+            if !self.visited.insert(func) {
+                continue;
+            }
+            match self.bp_addrs.get(&func) {
+                Some(func_bps) => bps.extend(func_bps),
+                None => {
+                    if let Some(callees) = self.call_graph.get(&func) {
+                        to_visit.extend(callees);
+                    }
+                }
+            }
+        }
+        bps
+    }
+
+    /// Returns the breakpoints to place now that `func` was entered
+    pub fn callees_of(&mut self, func: u64) -> Vec<u64> {
+        let callees = self.call_graph.get(&func).cloned().unwrap_or_default();
+        self.reachable(&callees)
+    }
+
+    /// Forgets which breakpoints were returned, after they were removed
+    pub fn reset(&mut self) {
+        self.visited.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// main calls init and run, run calls the untraced helper which calls work, work and
+    /// init both call log, and work calls itself
+    fn placer() -> LazyPlacer {
+        let call_graph = [
+            (0x100, vec![0x200, 0x300]),
+            (0x300, vec![0x400]),
+            (0x400, vec![0x500]),
+            (0x500, vec![0x500, 0x600]),
+            (0x200, vec![0x600]),
+        ];
+        let traced = [0x100, 0x200, 0x300, 0x500, 0x600];
+        LazyPlacer::new(
+            call_graph.into_iter().collect(),
+            traced
+                .into_iter()
+                .map(|func| (func, vec![func, func + 4]))
+                .collect(),
+        )
+    }
+
+    fn sorted(mut bps: Vec<u64>) -> Vec<u64> {
+        bps.sort_unstable();
+        bps
+    }
+
+    #[test]
+    fn places_the_callees_as_their_callers_are_entered() {
+        let mut placer = placer();
+        assert_eq!(placer.reachable(&[0x100]), [0x100, 0x104]);
+        assert_eq!(
+            sorted(placer.callees_of(0x100)),
+            [0x200, 0x204, 0x300, 0x304]
+        );
+        // the callees of the untraced helper are placed with the function calling it
+        assert_eq!(sorted(placer.callees_of(0x300)), [0x500, 0x504]);
+        assert_eq!(sorted(placer.callees_of(0x500)), [0x600, 0x604]);
+    }
+
+    #[test]
+    fn places_every_breakpoint_once() {
+        let mut placer = placer();
+        placer.reachable(&[0x100]);
+        placer.callees_of(0x100);
+        placer.callees_of(0x200);
+        // log was placed for init already, and work calling itself adds nothing
+        assert_eq!(sorted(placer.callees_of(0x300)), [0x500, 0x504]);
+        assert!(placer.callees_of(0x500).is_empty());
+        assert!(placer.callees_of(0x500).is_empty());
+        assert!(placer.reachable(&[0x100, 0x300]).is_empty());
+    }
+
+    #[test]
+    fn unknown_functions_have_no_callees() {
+        let mut placer = placer();
+        assert!(placer.callees_of(0x999).is_empty());
+        assert!(placer.reachable(&[0x999]).is_empty());
+        assert!(placer.callees_of(0x600).is_empty());
+    }
+}
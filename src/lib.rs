@@ -0,0 +1,33 @@
+//! A function tracer for x86_64 Linux binaries
+//!
+//! [`Tracer`] runs a binary under ptrace with breakpoints on its functions and reports every
+//! call with its arguments and return value, as text and to the [`TraceHandler`]s given to it.
+
+pub mod backtrace;
+mod breakpoint;
+mod cache;
+pub mod cli;
+pub mod condition;
+mod debugreg;
+pub mod defs;
+pub mod error;
+pub mod filter;
+pub mod function;
+pub mod handler;
+pub mod inject;
+mod lazy;
+pub mod limits;
+pub mod lines;
+pub mod process_ext;
+pub mod ptrace_engine;
+pub mod script;
+mod tracer;
+mod utils;
+mod watch;
+
+pub use crate::cli::{FuncSource, WatchSpec};
+pub use crate::defs::{DebuggerEngine, ProcessInfo, Result};
+pub use crate::error::Error;
+pub use crate::function::{FormalParameter, Function};
+pub use crate::handler::{Action, Event, TraceContext, TraceHandler};
+pub use crate::tracer::Tracer;
@@ -0,0 +1,143 @@
+//! Caps on how often a function is traced, so hot functions don't slow down the whole trace
+//!
+//! Every traced call costs two ptrace stops, a function which hits a cap has its breakpoint
+//! removed.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// Why a function stopped being traced
+#[derive(Debug, Clone, Copy)]
+pub enum Limit {
+    /// `--max-calls`
+    Calls(u64),
+    /// `--max-rate`, in calls per second
+    Rate(u64),
+}
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Limit::Calls(calls) => write!(f, "reached {} calls", calls),
+            Limit::Rate(rate) => write!(f, "called more than {} times per second", rate),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct CallStats {
+    calls: u64,
+    window_start: Instant,
+    window_calls: u64,
+    limited: Option<Limit>,
+}
+
+#[derive(Debug, Default)]
+pub struct CallLimits {
+    max_calls: Option<u64>,
+    max_rate: Option<u64>,
+    /// keyed by function address
+    stats: HashMap<u64, CallStats>,
+}
+
+impl CallLimits {
+    pub fn new(max_calls: Option<u64>, max_rate: Option<u64>) -> Self {
+        Self {
+            max_calls,
+            max_rate,
+            stats: HashMap::new(),
+        }
+    }
+
+    /// Counts a call to the function at `address`, returns the limit it just reached
+    pub fn record(&mut self, address: u64) -> Option<Limit> {
+        if self.max_calls.is_none() && self.max_rate.is_none() {
+            return None;
+        }
+        let now = Instant::now();
+        let stats = self.stats.entry(address).or_insert(CallStats {
+            calls: 0,
+            window_start: now,
+            window_calls: 0,
+            limited: None,
+        });
+        stats.calls += 1;
+        if now.duration_since(stats.window_start) >= Duration::from_secs(1) {
+            stats.window_start = now;
+            stats.window_calls = 0;
+        }
+        stats.window_calls += 1;
+
+        let limit = match (self.max_calls, self.max_rate) {
+            (Some(max), _) if stats.calls >= max => Limit::Calls(max),
+            (_, Some(rate)) if stats.window_calls > rate => Limit::Rate(rate),
+            _ => return None,
+        };
+        stats.limited = Some(limit);
+        Some(limit)
+    }
+
+    /// The functions which hit a limit, with the number of calls traced until then
+    pub fn limited(&self) -> impl Iterator<Item = (u64, u64, Limit)> + '_ {
+        self.stats
+            .iter()
+            .filter_map(|(&address, stats)| Some((address, stats.calls, stats.limited?)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unlimited_calls_are_not_counted() {
+        let mut limits = CallLimits::new(None, None);
+        for _ in 0..1000 {
+            assert!(limits.record(0x1000).is_none());
+        }
+        assert_eq!(limits.limited().count(), 0);
+    }
+
+    #[test]
+    fn max_calls_is_per_function() {
+        let mut limits = CallLimits::new(Some(3), None);
+        assert!(limits.record(0x1000).is_none());
+        assert!(limits.record(0x1000).is_none());
+        assert!(limits.record(0x2000).is_none());
+        assert!(matches!(limits.record(0x1000), Some(Limit::Calls(3))));
+        assert!(limits.record(0x2000).is_none());
+
+        let limited: Vec<_> = limits.limited().collect();
+        assert_eq!(limited.len(), 1);
+        assert!(matches!(limited[0], (0x1000, 3, Limit::Calls(3))));
+    }
+
+    #[test]
+    fn max_rate_is_exceeded_within_a_second() {
+        let mut limits = CallLimits::new(None, Some(2));
+        assert!(limits.record(0x1000).is_none());
+        assert!(limits.record(0x1000).is_none());
+        assert!(matches!(limits.record(0x1000), Some(Limit::Rate(2))));
+        assert!(matches!(
+            limits.limited().next(),
+            Some((0x1000, 3, Limit::Rate(2)))
+        ));
+    }
+
+    #[test]
+    fn max_calls_wins_over_max_rate() {
+        let mut limits = CallLimits::new(Some(2), Some(1));
+        assert!(limits.record(0x1000).is_none());
+        assert!(matches!(limits.record(0x1000), Some(Limit::Calls(2))));
+    }
+
+    #[test]
+    fn displays_limits() {
+        assert_eq!(Limit::Calls(10).to_string(), "reached 10 calls");
+        assert_eq!(
+            Limit::Rate(5).to_string(),
+            "called more than 5 times per second"
+        );
+    }
+}
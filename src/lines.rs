@@ -0,0 +1,354 @@
+//! Source lines of the addresses of the binary, from the DWARF line programs and the inlined
+//! calls of the debug info
+
+use std::collections::HashMap;
+use std::fmt;
+use std::rc::Rc;
+
+use crate::defs::Result;
+use crate::function::{dwarf_line_table, Function};
+
+/// A line of a source file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Location {
+    pub file: String,
+    pub line: u64,
+}
+
+/// The line of an address and the inlined calls it is part of
+#[derive(Debug, Clone)]
+pub struct SourceLocation {
+    pub location: Location,
+    /// the inlined functions the line is in and where they were inlined, innermost first
+    pub inlined: Vec<(String, Location)>,
+}
+
+/// The address of a row and its file and line, `None` where a sequence ends
+pub(crate) type Row = (u64, Option<(usize, u64)>);
+
+/// The file names, line rows and inlined calls of a compilation unit, the file indexes are
+/// into the file names of the unit
+pub(crate) type UnitLines = (Vec<String>, Vec<Row>, Vec<InlinedRange>);
+
+/// A range of code inlined from another function
+#[derive(Debug)]
+pub(crate) struct InlinedRange {
+    pub begin: u64,
+    pub end: u64,
+    /// where the inlined instance starts, as it is traced
+    pub entry: u64,
+    /// nesting level of the inlined call in the debug info, deeper calls are inside others
+    pub depth: usize,
+    pub function: String,
+    /// file and line of the call which was inlined
+    pub call: (usize, u64),
+}
+
+/// The rows of the line programs and the inlined ranges, sorted by address
+#[derive(Debug, Default)]
+pub struct LineTable {
+    files: Vec<String>,
+    rows: Vec<Row>,
+    inlined: Vec<InlinedRange>,
+    /// the furthest end of the inlined ranges up to each one
+    inlined_end: Vec<u64>,
+    /// the inlined ranges of the instances starting at each address
+    inlined_entries: HashMap<u64, Vec<usize>>,
+}
+
+impl LineTable {
+    /// Loads the line programs of the binary, the addresses are not relocated
+    pub fn load(obj: &object::File) -> Result<Self> {
+        dwarf_line_table(obj)
+    }
+
+    /// Merges the files, rows and inlined ranges of each unit
+    pub(crate) fn new(units: Vec<UnitLines>) -> Self {
+        let mut table = Self::default();
+        for (files, rows, inlined) in units {
+            let first = table.files.len();
+            table.files.extend(files);
+            let rows = rows
+                .into_iter()
+                .map(|(address, row)| (address, row.map(|(file, line)| (first + file, line))));
+            table.rows.extend(rows);
+            let inlined = inlined.into_iter().map(|range| InlinedRange {
+                call: (first + range.call.0, range.call.1),
+                ..range
+            });
+            table.inlined.extend(inlined);
+        }
+        // keeps the end of a sequence before a row starting at the same address
+        table
+            .rows
+            .sort_by_key(|&(address, row)| (address, row.is_some()));
+        table.inlined.sort_by_key(|range| range.begin);
+        table.inlined_end = table
+            .inlined
+            .iter()
+            .scan(0, |end, range| {
+                *end = range.end.max(*end);
+                Some(*end)
+            })
+            .collect();
+        for (index, range) in table.inlined.iter().enumerate() {
+            table
+                .inlined_entries
+                .entry(range.entry)
+                .or_default()
+                .push(index);
+        }
+        table
+    }
+
+    /// The line the code at `address` comes from
+    pub fn lookup(&self, address: u64) -> Option<Location> {
+        let index = self
+            .rows
+            .partition_point(|&(row_address, _)| row_address <= address);
+        let (_, row) = self.rows.get(index.checked_sub(1)?)?;
+        let (file, line) = (*row)?;
+        Some(self.location(file, line))
+    }
+
+    /// The line the code at `address` comes from and the inlined calls leading to it
+    pub fn locate(&self, address: u64) -> Option<SourceLocation> {
+        let location = self.lookup(address)?;
+        let inlined = self
+            .inlined_at(address)
+            .into_iter()
+            .map(|range| {
+                (
+                    range.function.clone(),
+                    self.location(range.call.0, range.call.1),
+                )
+            })
+            .collect();
+        Some(SourceLocation { location, inlined })
+    }
+
+    /// Where the inlined instance of `function` starting at `entry` was called, and the
+    /// inlined calls the call itself is in
+    pub fn inlined_call(&self, entry: u64, function: &str) -> Option<SourceLocation> {
+        let range = self
+            .inlined_entries
+            .get(&entry)?
+            .iter()
+            .map(|&index| &self.inlined[index])
+            .find(|range| range.function == function)?;
+        let inlined = self
+            .inlined_at(range.begin)
+            .into_iter()
+            .filter(|outer| outer.depth < range.depth)
+            .map(|outer| {
+                (
+                    outer.function.clone(),
+                    self.location(outer.call.0, outer.call.1),
+                )
+            })
+            .collect();
+        Some(SourceLocation {
+            location: self.location(range.call.0, range.call.1),
+            inlined,
+        })
+    }
+
+    /// The inlined ranges covering `address`, innermost first
+    fn inlined_at(&self, address: u64) -> Vec<&InlinedRange> {
+        let index = self.inlined.partition_point(|range| range.begin <= address);
+        let mut ranges: Vec<_> = (0..index)
+            .rev()
+            .take_while(|&i| self.inlined_end[i] > address)
+            .map(|i| &self.inlined[i])
+            .filter(|range| range.end > address)
+            .collect();
+        ranges.sort_by_key(|range| std::cmp::Reverse(range.depth));
+        ranges
+    }
+
+    fn location(&self, file: usize, line: u64) -> Location {
+        Location {
+            file: self.files[file].clone(),
+            line,
+        }
+    }
+}
+
+/// Finds the source locations of the calls for the printed trace
+pub(crate) struct Sources {
+    lines: Rc<LineTable>,
+    /// where the binary was loaded
+    base: u64,
+    /// the lines of the source files read so far, `None` without snippets
+    files: Option<HashMap<String, Option<Vec<String>>>>,
+}
+
+impl Sources {
+    pub fn new(lines: Rc<LineTable>, base: u64, snippets: bool) -> Self {
+        Self {
+            lines,
+            base,
+            files: snippets.then(HashMap::new),
+        }
+    }
+
+    /// Where `function` was called from, found from the address the call returns to
+    pub fn call_site(
+        &self,
+        function: &Function,
+        return_address: Option<u64>,
+    ) -> Option<SourceLocation> {
+        if function.inlined {
+            return self
+                .lines
+                .inlined_call(function.address - self.base, &function.name);
+        }
+        // the call instruction is right before the return address
+        let call = return_address?.checked_sub(self.base + 1)?;
+        self.lines.locate(call)
+    }
+
+    /// The line of source at `location`, if snippets are printed and the file can be read
+    pub fn snippet(&mut self, location: &Location) -> Option<&str> {
+        let files = self.files.as_mut()?;
+        let lines = files.entry(location.file.clone()).or_insert_with(|| {
+            let source = std::fs::read_to_string(&location.file).ok()?;
+            Some(source.lines().map(str::to_owned).collect())
+        });
+        let line = lines
+            .as_ref()?
+            .get(location.line.checked_sub(1)? as usize)?;
+        Some(line.trim())
+    }
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.file, self.line)
+    }
+}
+
+impl fmt::Display for SourceLocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.location)?;
+        for (function, call) in self.inlined.iter() {
+            write!(f, ", {} inlined at {}", function, call)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn location(file: &str, line: u64) -> Location {
+        Location {
+            file: file.to_owned(),
+            line,
+        }
+    }
+
+    fn inlined(
+        function: &str,
+        begin: u64,
+        end: u64,
+        depth: usize,
+        call: (usize, u64),
+    ) -> InlinedRange {
+        InlinedRange {
+            begin,
+            end,
+            entry: begin,
+            depth,
+            function: function.to_owned(),
+            call,
+        }
+    }
+
+    /// main.c calls `outer` inlined at line 2, which calls `inner` from util.h at line 10,
+    /// which calls `leaf` at line 11 right at its start. A second unit in other.c starts where
+    /// the sequence of the first one ends
+    fn table() -> LineTable {
+        let main = (
+            vec!["main.c".to_owned(), "util.h".to_owned()],
+            vec![
+                (0x100, Some((0, 1))),
+                (0x110, Some((0, 2))),
+                (0x120, Some((1, 10))),
+                (0x130, None),
+            ],
+            vec![
+                inlined("leaf", 0x118, 0x120, 3, (1, 11)),
+                inlined("outer", 0x110, 0x130, 1, (0, 2)),
+                inlined("inner", 0x118, 0x128, 2, (1, 10)),
+            ],
+        );
+        let other = (
+            vec!["other.c".to_owned()],
+            vec![(0x130, Some((0, 5))), (0x140, None)],
+            vec![],
+        );
+        LineTable::new(vec![main, other])
+    }
+
+    fn functions(ranges: Vec<&InlinedRange>) -> Vec<&str> {
+        ranges.iter().map(|range| range.function.as_str()).collect()
+    }
+
+    #[test]
+    fn looks_up_the_row_before_an_address() {
+        let table = table();
+        assert_eq!(table.lookup(0xff), None);
+        assert_eq!(table.lookup(0x100), Some(location("main.c", 1)));
+        assert_eq!(table.lookup(0x10f), Some(location("main.c", 1)));
+        assert_eq!(table.lookup(0x110), Some(location("main.c", 2)));
+        assert_eq!(table.lookup(0x12f), Some(location("util.h", 10)));
+    }
+
+    #[test]
+    fn sequences_end_before_the_next_one_starts() {
+        let table = table();
+        // the end of the first sequence is at the start of the second one
+        assert_eq!(table.lookup(0x130), Some(location("other.c", 5)));
+        assert_eq!(table.lookup(0x140), None);
+        assert_eq!(table.lookup(0x1000), None);
+    }
+
+    #[test]
+    fn finds_the_inlined_ranges_innermost_first() {
+        let table = table();
+        assert!(table.inlined_at(0x10f).is_empty());
+        assert_eq!(functions(table.inlined_at(0x110)), ["outer"]);
+        assert_eq!(
+            functions(table.inlined_at(0x118)),
+            ["leaf", "inner", "outer"]
+        );
+        // the ends are exclusive
+        assert_eq!(functions(table.inlined_at(0x120)), ["inner", "outer"]);
+        assert_eq!(functions(table.inlined_at(0x128)), ["outer"]);
+        assert!(table.inlined_at(0x130).is_empty());
+    }
+
+    #[test]
+    fn finds_the_call_of_nested_instances_at_the_same_entry() {
+        let table = table();
+        let leaf = table.inlined_call(0x118, "leaf").unwrap();
+        assert_eq!(leaf.location, location("util.h", 11));
+        assert_eq!(
+            leaf.inlined,
+            [
+                ("inner".to_owned(), location("util.h", 10)),
+                ("outer".to_owned(), location("main.c", 2)),
+            ]
+        );
+        let inner = table.inlined_call(0x118, "inner").unwrap();
+        assert_eq!(inner.location, location("util.h", 10));
+        assert_eq!(inner.inlined, [("outer".to_owned(), location("main.c", 2))]);
+        let outer = table.inlined_call(0x110, "outer").unwrap();
+        assert_eq!(outer.location, location("main.c", 2));
+        assert!(outer.inlined.is_empty());
+        assert!(table.inlined_call(0x118, "outer").is_none());
+        assert!(table.inlined_call(0x100, "leaf").is_none());
+    }
+}
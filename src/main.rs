@@ -1,32 +1,12 @@
-use std::collections::HashMap;
-use std::fmt::Debug;
-use std::path::Path;
-use std::process::Command;
+use std::path::PathBuf;
 
 use clap::Clap;
-use cpp_demangle::Symbol;
-use defs::ProcessInfo;
-use object::Object;
-use process_ext::ProcessExt;
-use std::string::ToString;
-use tracing::{debug, warn};
+use ftrace_rs::condition::Condition;
+use ftrace_rs::filter::{runtime_functions, AddressRange, FunctionFilter, Glob};
+use ftrace_rs::inject::Injection;
+use ftrace_rs::{FuncSource, Result, Tracer, WatchSpec};
 use tracing_subscriber;
 
-mod breakpoint;
-mod cli;
-mod defs;
-mod error;
-mod function;
-mod process_ext;
-mod ptrace_engine;
-mod utils;
-
-use crate::cli::FuncSource;
-use crate::defs::Result;
-use crate::defs::{DebuggerEngine, DebuggerStatus};
-use crate::function::{get_functions, get_functions_dwarf};
-use crate::utils::get_base_region;
-
 #[derive(Clap)]
 pub struct Opts {
     /// How to resolve the functions in the binary
@@ -39,146 +19,199 @@ pub struct Opts {
     #[clap(short, long)]
     only: Option<regex::Regex>,
 
-    /// Path to the binary to be traced
-    binary: String,
-}
+    /// Also trace the startup and shutdown functions of the C, C++ and Rust runtimes, like
+    /// `_start` or `frame_dummy`, which are skipped by default
+    #[clap(long)]
+    trace_runtime: bool,
 
-fn main() -> Result<()> {
-    tracing_subscriber::fmt::init();
-    let opts: Opts = Opts::parse();
+    /// Only print the calls made while `main` runs
+    #[clap(long)]
+    from_main: bool,
 
-    let binary = Path::new(&opts.binary);
+    /// Only trace the functions declared in a matching source file, e.g. `src/net/*`
+    #[clap(long, multiple_occurrences = true, number_of_values = 1)]
+    file: Vec<Glob>,
 
-    let bin_data = std::fs::read(binary)?;
-    let obj_file = object::File::parse(&*bin_data)?;
+    /// Only trace the functions of a matching compile unit
+    #[clap(long, multiple_occurrences = true, number_of_values = 1)]
+    unit: Vec<Glob>,
 
-    let binary_is_relocatable = matches!(
-        obj_file.kind(),
-        object::ObjectKind::Dynamic | object::ObjectKind::Relocatable
-    );
+    /// Only trace the Rust functions of a crate
+    #[clap(long = "crate", multiple_occurrences = true, number_of_values = 1)]
+    crate_name: Vec<String>,
 
-    let cmd = Command::new(binary);
-    let (engine, last_process) = ptrace_engine::PtraceEngine::spawn(cmd)?;
+    /// Only trace the functions of a matching object file. Shared libraries aren't traced, so
+    /// this has to match the binary
+    #[clap(long, multiple_occurrences = true, number_of_values = 1)]
+    object: Vec<Glob>,
 
-    let maps = last_process.get_memory_maps()?;
-    let base_region = get_base_region(&maps, binary.canonicalize()?.to_str().unwrap()).unwrap();
-    debug!(?maps, ?binary_is_relocatable, ?base_region);
-    let mut funcs = match opts.source {
-        FuncSource::Heuristic => get_functions(&obj_file),
-        FuncSource::Dwarf => get_functions_dwarf(binary.to_str().unwrap(), &obj_file)?,
-    };
-    debug!(?funcs);
+    /// Only trace the functions starting in an address range of the binary, given as
+    /// `0xstart-0xend` or `0xstart+0xlen`
+    #[clap(long, multiple_occurrences = true, number_of_values = 1)]
+    addr: Vec<AddressRange>,
 
-    for mut func in funcs.iter_mut() {
-        if binary_is_relocatable {
-            func.prologue_end_addr = func.prologue_end_addr.map(|x| x + base_region.start);
-            func.address += base_region.start;
-        }
-        func.name = match Symbol::new(&func.name).map(|op| op.to_string()) {
-            Ok(name) => name,
-            // rustc demangle will return the original if it cant parser
-            Err(_) => rustc_demangle::demangle(&func.name).to_string(),
-        };
-    }
-    debug!(?funcs);
-    let to_keep = |name: &str| match (&opts.only, &opts.ignore) {
-        (Some(only), Some(ignore)) => (only.is_match(name) && !ignore.is_match(name)),
-        (Some(only), None) => only.is_match(name),
-        (None, Some(ignore)) => !ignore.is_match(name),
-        (None, None) => true,
-    };
+    /// Also trace inlined instances of functions (needs DWARF)
+    #[clap(long)]
+    inline: bool,
 
-    // filter functions
-    funcs.retain(|f| to_keep(&f.name));
+    /// Always analyse the binary instead of using the functions cached from a previous run
+    #[clap(long)]
+    no_cache: bool,
 
-    start_trace(engine, last_process, funcs)?;
-    Ok(())
+    /// Number of threads used to analyse the binary, defaults to the number of cores
+    #[clap(short, long)]
+    jobs: Option<usize>,
+
+    /// Trace the matching functions with hardware breakpoints, which leave the code untouched.
+    /// Only 4 are available, further functions get normal breakpoints
+    #[clap(long)]
+    hw: Option<regex::Regex>,
+
+    /// Only print the calls of a function whose arguments match a condition, given as
+    /// `function:expression`, e.g. `fact:n>3 && n<10` or `load_config:path=~"^/etc"`.
+    /// Parameters are named as in the debug info, or `arg0`, `arg1`..., registers by their
+    /// name. The function must be defined in the binary
+    #[clap(long, multiple_occurrences = true, number_of_values = 1)]
+    cond: Vec<Condition>,
+
+    /// Only print the calls which match a --cond and everything they call
+    #[clap(long)]
+    cond_subtree: bool,
+
+    /// Don't print calls nested deeper than this, counted from the focused call if any
+    #[clap(long)]
+    max_depth: Option<usize>,
+
+    /// Only print the calls to matching functions and everything they call
+    #[clap(long)]
+    focus: Option<regex::Regex>,
+
+    /// Print the call stack under the calls to matching functions, unwound with the CFI of
+    /// the binary so callers without frame pointers show up too
+    #[clap(long)]
+    backtrace: Option<regex::Regex>,
+
+    /// Print where each call comes from and where the function is declared, from the DWARF
+    /// line info
+    #[clap(long)]
+    locations: bool,
+
+    /// Also print the source line of each call under it, implies --locations
+    #[clap(long)]
+    snippets: bool,
+
+    /// Stop tracing a function after this many calls
+    #[clap(long)]
+    max_calls: Option<u64>,
+
+    /// Stop tracing a function once it is called more than this many times per second
+    #[clap(long)]
+    max_rate: Option<u64>,
+
+    /// Report the writes to a global variable or field (`var.field`) or to an address of the
+    /// binary (`0x...`, like --addr), optionally giving the number of bytes with `:len`. Uses
+    /// debug registers, which are shared with --hw
+    #[clap(long, multiple_occurrences = true, number_of_values = 1)]
+    watch: Vec<WatchSpec>,
+
+    /// Also report the reads of the watched variables
+    #[clap(long)]
+    watch_reads: bool,
+
+    /// Force a function to fail, e.g. `alloc_node=0` or `read_config:ret=-1,errno=EIO,skip`.
+    /// Can also rewrite arguments (`open_log:arg1=0`) and only pick the `nth=N` call or calls
+    /// with the probability `prob=P`. The function must be defined in the binary, shared
+    /// library imports aren't traced, and errno can only be set in statically linked binaries
+    #[clap(long, multiple_occurrences = true, number_of_values = 1)]
+    inject: Vec<Injection>,
+
+    /// Run a Rhai script at the reported calls, see the `script` module for its hooks
+    #[clap(long)]
+    script: Option<PathBuf>,
+
+    /// Only break at the entry of functions. By default functions with parameters stored
+    /// relative to the frame base are also stopped at the end of their prologue, where those
+    /// parameters have been stored
+    #[clap(long)]
+    no_prologue: bool,
+
+    /// Only set breakpoints on functions once they become reachable through direct calls,
+    /// which speeds up the start of binaries with a lot of functions
+    #[clap(long)]
+    lazy: bool,
+
+    /// Path to the binary to be traced
+    binary: String,
 }
 
-fn start_trace<E>(
-    mut engine: E,
-    mut last_process: E::Process,
-    funcs: Vec<function::Function>,
-) -> Result<()>
-where
-    E: DebuggerEngine,
-    E::Process: ProcessInfo + Debug,
-{
-    let mut funcs_map = HashMap::new();
-    let mut funcs_prologue_map = HashMap::new();
-
-    for func in funcs.into_iter() {
-        let bp_addr = if let Some(start) = func.prologue_end_addr {
-            funcs_prologue_map.insert(start, func);
-            start
-        } else {
-            let addr = func.address;
-            funcs_map.insert(func.address, func);
-            addr
-        };
-        debug!("breakpoint set at {}", bp_addr);
-        engine.set_breakpoint(&mut last_process, bp_addr)?;
+fn main() -> Result<()> {
+    tracing_subscriber::fmt::init();
+    let opts: Opts = Opts::parse();
+    if let Some(jobs) = opts.jobs {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(jobs)
+            .build_global()
+            .expect("the global thread pool is only built once");
+    }
+
+    let filter = FunctionFilter {
+        only: opts.only,
+        ignore: opts.ignore,
+        runtime: (!opts.trace_runtime).then(runtime_functions),
+        files: opts.file,
+        units: opts.unit,
+        crates: opts.crate_name,
+        objects: opts.object,
+        ranges: opts.addr,
+    };
+    let mut tracer = Tracer::new(opts.binary)
+        .source(opts.source)
+        .inline(opts.inline)
+        .cache(!opts.no_cache)
+        .lazy(opts.lazy)
+        .prologue(!opts.no_prologue)
+        .filter(filter)
+        .from_main(opts.from_main)
+        .watch_reads(opts.watch_reads)
+        .locations(opts.locations)
+        .snippets(opts.snippets)
+        .cond_subtree(opts.cond_subtree);
+    if let Some(hw) = opts.hw {
+        tracer = tracer.hardware_breakpoints(hw);
+    }
+    for watch in opts.watch {
+        tracer = tracer.watch(watch);
+    }
+    for cond in opts.cond {
+        tracer = tracer.condition(cond);
+    }
+    if let Some(max_depth) = opts.max_depth {
+        tracer = tracer.max_depth(max_depth);
+    }
+    if let Some(focus) = opts.focus {
+        tracer = tracer.focus(focus);
     }
-    engine.cont(&mut last_process)?;
-    let mut depth = 0;
-
-    // TODO: this wait and cont thingy is kinda falky
-    while let Ok(status) = engine.wait() {
-        debug!(?status);
-        match status {
-            DebuggerStatus::BreakpointHit(process, address) => {
-                last_process = process;
-                if let Some(func) = funcs_map.get(&address) {
-                    depth += 1;
-                    let registers = last_process.get_registers().unwrap();
-                    print_function(&last_process, func, depth)?;
-                    let ret_addr = last_process.read_u64_at(registers.rsp)?;
-                    if ret_addr > 1 {
-                        // println!("{:0x}", ret_addr);
-                        engine.set_breakpoint(&mut last_process, ret_addr)?;
-                    }
-                } else if let Some(func) = funcs_prologue_map.get(&address) {
-                    depth += 1;
-                    let registers = last_process.get_registers().unwrap();
-                    print_function(&last_process, func, depth)?;
-                    let base_ptr = last_process.read_u64_at(registers.rbp)?;
-                    if base_ptr > 1 {
-                        let ret_addr = last_process.read_u64_at(base_ptr + 8)?;
-                        engine.set_breakpoint(&mut last_process, ret_addr)?;
-                    }
-                } else {
-                    // TODO: this is the ret this should be better lol
-                    let registers = last_process.get_registers().unwrap();
-                    println!("{}{}", str::repeat("| ", depth), registers.rax);
-                    depth -= 1;
-                }
-            }
-            DebuggerStatus::Exited(_pid, _exit_code) => {
-                break;
-            }
-            DebuggerStatus::Stopped(pid) => {
-                warn!(?pid, "got Stopped event");
-                break;
-            }
-            _ => {}
+    if let Some(backtrace) = opts.backtrace {
+        tracer = tracer.backtrace(backtrace);
+    }
+    if let Some(max_calls) = opts.max_calls {
+        tracer = tracer.max_calls(max_calls);
+    }
+    if let Some(max_rate) = opts.max_rate {
+        tracer = tracer.max_rate(max_rate);
+    }
+    for injection in opts.inject {
+        tracer = tracer.inject(injection);
+    }
+    if let Some(script) = opts.script {
+        tracer = tracer.script(script);
+    }
+    if let Err(err) = tracer.run() {
+        match std::error::Error::source(&err) {
+            Some(source) => eprintln!("error: {}: {}", err, source),
+            None => eprintln!("error: {}", err),
         }
-        engine.cont(&mut last_process).unwrap();
+        std::process::exit(1);
     }
     Ok(())
 }
-
-fn print_function<P: ProcessInfo>(
-    process: &P,
-    func: &function::Function,
-    depth: usize,
-) -> Result<()> {
-    let params = process.get_fn_param_values(&func.parameters)?;
-
-    println!(
-        "{}{}({})",
-        str::repeat("| ", depth),
-        func.name,
-        params.join(", ")
-    );
-    Ok(())
-}
@@ -1,63 +1,247 @@
-use crate::function::{FormalParameter, FormalParameterKind};
+use crate::function::{BaseType, BaseTypeEncoding, FormalParameter, FormalParameterKind, TypeKind};
 use crate::{
     defs::{ProcessInfo, Register, Registers, Result},
-    error::ParamFindingFailure,
+    error::{Error, ParamFindingFailure},
 };
 
 /// Generic helper functions for getting values from process
+///
+/// Parameters in memory are located from the canonical frame address (CFA) of the call, the
+/// value of RSP before the call instruction. It is found at the entry, without it the frame
+/// pointer is assumed to be set up for frame base relative parameters.
 pub trait ProcessExt {
     fn get_fn_param_values(
         &self,
         params: &[std::result::Result<FormalParameter, ParamFindingFailure>],
+        cfa: Option<u64>,
     ) -> Result<Vec<String>>;
 
+    /// Formats the return value, to be called when the function returns
+    fn get_return_value(&self, ret: &FormalParameter) -> Result<String>;
+
+    fn format_param(
+        &self,
+        registers: &Registers,
+        param: &FormalParameter,
+        cfa: Option<u64>,
+    ) -> String;
+
+    /// Reads the raw bits of a parameter, zero extended
+    fn get_param_value(
+        &self,
+        registers: &Registers,
+        param: &FormalParameter,
+        cfa: Option<u64>,
+    ) -> Result<u64>;
+
+    /// Overwrites a parameter with the raw bits of `value`, truncated to its size
+    fn set_param_value(
+        &mut self,
+        param: &FormalParameter,
+        value: u64,
+        cfa: Option<u64>,
+    ) -> Result<()>;
+
+    /// Reads the value of a register, XMM registers give their low 64 bits
+    fn get_register_value(&self, registers: &Registers, reg: Register) -> Result<u64>;
+
     // TODO: can i make u64 generic?
     fn read_u64_at(&self, addr: u64) -> Result<u64>;
 
-    fn read_at_bytes(&self, addr: u64, n_bytes: usize) -> Result<Vec<u8>>;
+    /// Reads the NUL terminated string at `addr`, up to `max_len` bytes of it
+    fn read_c_string(&self, addr: u64, max_len: usize) -> Result<String>;
 }
 
-impl<T: ProcessInfo> ProcessExt for T {
+impl<T: ProcessInfo + ?Sized> ProcessExt for T {
     fn get_fn_param_values(
         &self,
         params: &[std::result::Result<FormalParameter, ParamFindingFailure>],
+        cfa: Option<u64>,
     ) -> Result<Vec<String>> {
         let registers = self.get_registers()?;
-        Ok(params
+        // the parameters living in memory are all read at once
+        let locations: Vec<_> = params
             .iter()
             .map(|param| match param {
-                Ok(param) => {
-                    use FormalParameterKind::*;
-                    match param.kind {
-                        Register(reg) => format!("{}", get_register(registers, reg)),
-                        Memory(mem) => {
-                            let bp = self
-                                .read_at_bytes(
-                                    // XXX: TODO: HACK: this +16 is plain wrong
-                                    (registers.rbp as i64 + 16 + mem.offset) as u64,
-                                    mem.size as usize,
-                                )
-                                .map(|v| format_data(&v, mem.size));
-                            format!("{:?}", bp)
-                        }
-                    }
-                }
-                Err(_) => "err".to_string(),
+                Ok(param) => memory_location(&registers, param, cfa),
+                Err(_) => None,
+            })
+            .collect();
+        let mut buffers = vec![[0u8; 8]; params.len()];
+        let mut reads: Vec<(u64, &mut [u8])> = locations
+            .iter()
+            .zip(buffers.iter_mut())
+            .filter_map(|(location, buffer)| {
+                location.map(|(address, size)| (address, &mut buffer[..size]))
+            })
+            .collect();
+        if self.read_many(&mut reads).is_err() {
+            // some of them are unreadable, read them one by one to find out which
+            return Ok(params
+                .iter()
+                .map(|param| match param {
+                    Ok(param) => self.format_param(&registers, param, cfa),
+                    Err(_) => "err".to_string(),
+                })
+                .collect());
+        }
+
+        Ok(params
+            .iter()
+            .zip(locations)
+            .zip(buffers.iter())
+            .map(|((param, location), buffer)| match (param, location) {
+                (Ok(param), Some((_, size))) => format_memory(param, Ok(&buffer[..size])),
+                (Ok(param), None) => self.format_param(&registers, param, cfa),
+                (Err(_), _) => "err".to_string(),
             })
             .collect())
     }
 
+    fn get_return_value(&self, ret: &FormalParameter) -> Result<String> {
+        let registers = self.get_registers()?;
+        Ok(self.format_param(&registers, ret, None))
+    }
+
+    fn format_param(
+        &self,
+        registers: &Registers,
+        param: &FormalParameter,
+        cfa: Option<u64>,
+    ) -> String {
+        match memory_location(registers, param, cfa) {
+            Some((address, size)) => {
+                let mut buffer = [0u8; 8];
+                let data = self
+                    .read_at(address, &mut buffer[..size])
+                    .map(|_| &buffer[..size])
+                    .map_err(Error::from);
+                format_memory(param, data)
+            }
+            None => match param.kind {
+                FormalParameterKind::Register(reg) => {
+                    match self.get_register_value(registers, reg) {
+                        Ok(value) => format_value(value, param.ty.as_ref()),
+                        Err(_) => "err".to_string(),
+                    }
+                }
+                _ => "err".to_string(),
+            },
+        }
+    }
+
+    fn get_param_value(
+        &self,
+        registers: &Registers,
+        param: &FormalParameter,
+        cfa: Option<u64>,
+    ) -> Result<u64> {
+        match (memory_location(registers, param, cfa), &param.kind) {
+            (Some((address, size)), _) => {
+                let mut buffer = [0u8; 8];
+                self.read_many(&mut [(address, &mut buffer[..size])])?;
+                Ok(u64::from_le_bytes(buffer))
+            }
+            (None, FormalParameterKind::Register(reg)) => self.get_register_value(registers, *reg),
+            (None, _) => unreachable!("only register parameters have no memory location"),
+        }
+    }
+
+    fn set_param_value(
+        &mut self,
+        param: &FormalParameter,
+        value: u64,
+        cfa: Option<u64>,
+    ) -> Result<()> {
+        let mut registers = self.get_registers()?;
+        match (memory_location(&registers, param, cfa), &param.kind) {
+            (Some((address, size)), _) => {
+                self.write_at(address, &value.to_le_bytes()[..size])?;
+            }
+            (None, FormalParameterKind::Register(reg)) => {
+                let register =
+                    register_mut(&mut registers, *reg).ok_or(Error::UnknownRegister(reg.0))?;
+                *register = value;
+                self.set_registers(registers)?;
+            }
+            (None, _) => unreachable!("only register parameters have no memory location"),
+        }
+        Ok(())
+    }
+
+    fn get_register_value(&self, registers: &Registers, reg: Register) -> Result<u64> {
+        if let Some(n) = xmm_index(reg) {
+            let fp_registers = self.get_fp_registers()?;
+            // the low 64 bits of the XMM register
+            let lo = fp_registers.xmm_space[n * 4] as u64;
+            let hi = fp_registers.xmm_space[n * 4 + 1] as u64;
+            return Ok(lo | (hi << 32));
+        }
+        get_register(registers, reg).ok_or(Error::UnknownRegister(reg.0))
+    }
+
     fn read_u64_at(&self, addr: u64) -> Result<u64> {
         let mut ret_addr: [u8; 8] = [0; 8];
         self.read_at(addr, &mut ret_addr)?;
         Ok(u64::from_le_bytes(ret_addr))
     }
 
-    fn read_at_bytes(&self, addr: u64, n_bytes: usize) -> Result<Vec<u8>> {
-        // TODO: use smallvec
-        let mut v = vec![0; n_bytes];
-        self.read_at(addr, &mut v[..])?;
-        Ok(v)
+    fn read_c_string(&self, addr: u64, max_len: usize) -> Result<String> {
+        let mut string = vec![];
+        let mut chunk = [0u8; 64];
+        while string.len() < max_len {
+            let address = addr + string.len() as u64;
+            // don't read across a page, the next one might not be mapped
+            let len = chunk.len().min((4096 - address % 4096) as usize);
+            let read = self.read_at(address, &mut chunk[..len])?;
+            if read == 0 {
+                break;
+            }
+            match chunk[..read].iter().position(|&b| b == 0) {
+                Some(nul) => {
+                    string.extend_from_slice(&chunk[..nul]);
+                    break;
+                }
+                None => string.extend_from_slice(&chunk[..read]),
+            }
+        }
+        string.truncate(max_len);
+        Ok(String::from_utf8_lossy(&string).into_owned())
+    }
+}
+
+/// Returns the address and size of a parameter living in memory
+fn memory_location(
+    registers: &Registers,
+    param: &FormalParameter,
+    cfa: Option<u64>,
+) -> Option<(u64, usize)> {
+    use FormalParameterKind::*;
+    let (address, mem) = match param.kind {
+        Register(_) => return None,
+        // the frame base is the CFA, which is 16 bytes above RBP once the frame pointer is set up
+        Memory(mem) => (
+            (cfa.unwrap_or(registers.rbp + 16) as i64 + mem.offset) as u64,
+            mem,
+        ),
+        // at the function entry RSP points at the return address, right below the CFA
+        Stack(mem) => (
+            (cfa.unwrap_or(registers.rsp + 8) as i64 + mem.offset) as u64,
+            mem,
+        ),
+    };
+    Some((address, (mem.size as usize).min(8)))
+}
+
+/// Formats the bytes read for a parameter living in memory
+fn format_memory(param: &FormalParameter, data: Result<&[u8]>) -> String {
+    let size = data.as_ref().map_or(0, |data| data.len() as u64);
+    match param.kind {
+        FormalParameterKind::Memory(_) => format!("{:?}", data.map(|v| format_data(v, size))),
+        _ => match data {
+            Ok(v) => format_data(v, size),
+            Err(_) => "err".to_string(),
+        },
     }
 }
 
@@ -66,18 +250,60 @@ fn format_data(data: &[u8], size: u64) -> String {
         8 => format!("{}", u64::from_le_bytes(data[0..8].try_into().unwrap())),
         4 => format!("{}", u32::from_le_bytes(data[0..4].try_into().unwrap())),
         2 => format!("{}", u16::from_le_bytes(data[0..2].try_into().unwrap())),
+        1 => format!("{}", data[0]),
         _ => "not yet implemenented".to_string(),
     }
 }
 
-fn get_register(registers: Registers, register: Register) -> u64 {
-    match register {
-        gimli::X86_64::RDI => registers.rdi,
-        gimli::X86_64::RSI => registers.rsi,
-        gimli::X86_64::RDX => registers.rdx,
-        gimli::X86_64::RCX => registers.rcx,
-        _ => {
-            panic!("register not found")
+/// Formats a register value according to the type, if one is known
+fn format_value(value: u64, ty: Option<&TypeKind>) -> String {
+    match ty {
+        Some(TypeKind::BaseType(BaseType {
+            size,
+            encoding: BaseTypeEncoding::Float,
+        })) => match size {
+            4 => format!("{}", f32::from_bits(value as u32)),
+            _ => format!("{}", f64::from_bits(value)),
+        },
+        Some(TypeKind::BaseType(BaseType { size, .. })) if *size < 8 => {
+            format!("{}", value & ((1 << (size * 8)) - 1))
         }
+        _ => format!("{}", value),
+    }
+}
+
+fn xmm_index(register: Register) -> Option<usize> {
+    let n = register.0.checked_sub(gimli::X86_64::XMM0.0)?;
+    if n < 16 {
+        Some(n as usize)
+    } else {
+        None
     }
 }
+
+fn get_register(registers: &Registers, register: Register) -> Option<u64> {
+    register_mut(&mut registers.clone(), register).copied()
+}
+
+/// The slot of a general purpose register
+pub(crate) fn register_mut(registers: &mut Registers, register: Register) -> Option<&mut u64> {
+    Some(match register {
+        gimli::X86_64::RAX => &mut registers.rax,
+        gimli::X86_64::RBX => &mut registers.rbx,
+        gimli::X86_64::RCX => &mut registers.rcx,
+        gimli::X86_64::RDX => &mut registers.rdx,
+        gimli::X86_64::RSI => &mut registers.rsi,
+        gimli::X86_64::RDI => &mut registers.rdi,
+        gimli::X86_64::RBP => &mut registers.rbp,
+        gimli::X86_64::RSP => &mut registers.rsp,
+        gimli::X86_64::R8 => &mut registers.r8,
+        gimli::X86_64::R9 => &mut registers.r9,
+        gimli::X86_64::R10 => &mut registers.r10,
+        gimli::X86_64::R11 => &mut registers.r11,
+        gimli::X86_64::R12 => &mut registers.r12,
+        gimli::X86_64::R13 => &mut registers.r13,
+        gimli::X86_64::R14 => &mut registers.r14,
+        gimli::X86_64::R15 => &mut registers.r15,
+        _ => return None,
+    })
+}
@@ -4,32 +4,51 @@
 //! - A debugger using ptrace: https://blog.tartanllama.xyz/writing-a-linux-debugger-setup/
 //! - Mac vmmap in Rust: https://jvns.ca/blog/2018/01/26/mac-memory-maps/
 
+use std::cell::RefCell;
 use std::collections::HashMap;
+use std::fs::File;
 use std::path::PathBuf;
 use std::process::Child;
+use std::rc::Rc;
 use std::{os::unix::prelude::CommandExt, process::Command};
 
 use nix::sys::ptrace;
+use nix::sys::signal::{self, Signal};
+use nix::sys::uio::{process_vm_readv, IoVec, RemoteIoVec};
 use nix::sys::wait::{self, WaitPidFlag, WaitStatus};
 use nix::unistd::Pid;
 use std::os::unix::fs::FileExt;
-use tracing::debug;
+use tracing::{debug, warn};
 
 use crate::breakpoint::Breakpoint;
+use crate::debugreg::{self, HardwareBreakpoint, Trigger};
 use crate::defs::{
-    DebuggerEngine, DebuggerStatus, MemoryRegion, ProcessInfo, Registers, Result,
+    DebuggerEngine, DebuggerStatus, FpRegisters, MemoryRegion, ProcessInfo, Registers, Result,
 };
+use crate::error::Error;
 use crate::utils::parse_address_without_0x;
 
+const PAGE_SIZE: u64 = 4096;
+
 pub struct PtraceEngine {
     breakpoints: HashMap<u64, Breakpoint>,
+    /// the breakpoints held by the debug registers, indexed by slot
+    hw_breakpoints: [Option<HardwareBreakpoint>; debugreg::SLOTS],
+    /// the traced processes, so their memory handles are reused
+    processes: HashMap<Pid, Process>,
+    /// a stop which happened while continuing and is still to be reported
+    pending: Option<DebuggerStatus<Process>>,
+    /// a signal to deliver which was held back to report the pending stop first
+    signal: Option<Signal>,
+    /// the software breakpoint last hit, which is disabled until the process continues
+    hit: Option<u64>,
 }
 
 impl DebuggerEngine for PtraceEngine {
     type Process = Process;
 
     fn set_breakpoint(&mut self, process: &mut Process, address: u64) -> Result<()> {
-        if self.breakpoints.get(&address).is_some() {
+        if self.has_breakpoint(address) {
             return Ok(());
         }
         let mut bp = Breakpoint::new(address);
@@ -38,14 +57,117 @@ impl DebuggerEngine for PtraceEngine {
         Ok(())
     }
 
+    fn set_breakpoints(&mut self, process: &mut Process, addresses: &[u64]) -> Result<()> {
+        let mut addresses: Vec<u64> = addresses
+            .iter()
+            .copied()
+            .filter(|&address| !self.has_breakpoint(address))
+            .collect();
+        addresses.sort_unstable();
+        addresses.dedup();
+        // patch the breakpoints of a page with a single read and write
+        for page in addresses.chunk_by(|a, b| a / PAGE_SIZE == b / PAGE_SIZE) {
+            let start = page[0];
+            let mut mem = vec![0; (page[page.len() - 1] - start + 1) as usize];
+            // a short read would leave zeroes which get restored over the code
+            process.read_many(&mut [(start, &mut mem[..])])?;
+            for &address in page {
+                let offset = (address - start) as usize;
+                self.breakpoints
+                    .insert(address, Breakpoint::enabled(address, mem[offset]));
+                mem[offset] = 0xcc;
+            }
+            process.write_at(start, &mem)?;
+        }
+        Ok(())
+    }
+
+    fn set_hw_breakpoint(&mut self, process: &mut Process, address: u64) -> Result<()> {
+        if self.has_breakpoint(address) {
+            return Ok(());
+        }
+        match self.hw_breakpoints.iter().position(Option::is_none) {
+            Some(slot) => {
+                let bp = HardwareBreakpoint::new(address, slot);
+                bp.enable(process.pid)?;
+                self.hw_breakpoints[slot] = Some(bp);
+                Ok(())
+            }
+            None => {
+                warn!(
+                    address,
+                    "no free debug register, using a software breakpoint"
+                );
+                self.set_breakpoint(process, address)
+            }
+        }
+    }
+
+    fn remove_breakpoint(&mut self, process: &mut Process, address: u64) -> Result<()> {
+        if let Some(mut bp) = self.breakpoints.remove(&address) {
+            bp.disable(process)?;
+        }
+        let slot = self.hw_breakpoints.iter().position(
+            |bp| matches!(bp, Some(bp) if bp.trigger == Trigger::Execute && bp.address == address),
+        );
+        if let Some(bp) = slot.and_then(|slot| self.hw_breakpoints[slot].take()) {
+            bp.disable(process.pid)?;
+        }
+        Ok(())
+    }
+
+    fn set_watchpoint(
+        &mut self,
+        process: &mut Process,
+        address: u64,
+        len: u64,
+        reads: bool,
+    ) -> Result<()> {
+        let chunks = debugreg::aligned_chunks(address, len);
+        let free: Vec<_> = (0..debugreg::SLOTS)
+            .filter(|&slot| self.hw_breakpoints[slot].is_none())
+            .collect();
+        if chunks.len() > free.len() {
+            return Err(Error::NoFreeDebugRegister(len));
+        }
+        let trigger = if reads {
+            Trigger::Access
+        } else {
+            Trigger::Write
+        };
+        for ((address, len), slot) in chunks.into_iter().zip(free) {
+            let bp = HardwareBreakpoint::watchpoint(address, len, trigger, slot);
+            bp.enable(process.pid)?;
+            self.hw_breakpoints[slot] = Some(bp);
+        }
+        Ok(())
+    }
+
     fn cont(&mut self, process: &mut Self::Process) -> Result<()> {
-        let pid = process.0;
-        if let Some(bp) = self.get_breakpoint(process)? {
-            ptrace::step(pid, None)?;
-            let _status = wait::waitpid(pid, None)?;
-            bp.enable(process)?;
+        self.resume(process, None)
+    }
+
+    fn cont_with_signal(&mut self, process: &mut Process, signal: Signal) -> Result<()> {
+        self.resume(process, Some(signal))
+    }
+
+    fn detach(&mut self, process: &mut Process) -> Result<()> {
+        for (_, mut bp) in self.breakpoints.drain() {
+            bp.disable(process)?;
         }
-        ptrace::cont(pid, None)?;
+        for bp in self.hw_breakpoints.iter_mut().filter_map(Option::take) {
+            bp.disable(process.pid)?;
+        }
+        self.pending = None;
+        ptrace::detach(process.pid, self.signal.take())?;
+        self.processes.remove(&process.pid);
+        Ok(())
+    }
+
+    fn kill(&mut self, process: &mut Process) -> Result<()> {
+        self.pending = None;
+        self.signal = None;
+        signal::kill(process.pid, Signal::SIGKILL)?;
         Ok(())
     }
 
@@ -58,15 +180,26 @@ impl DebuggerEngine for PtraceEngine {
 
     fn spawn(cmd: Command) -> Result<(Self, Process)> {
         let child = Self::spawn_cmd(cmd)?;
-        Ok((
-            Self {
-                breakpoints: HashMap::new(),
-            },
-            Process(Pid::from_raw(child.id() as i32)),
-        ))
+        let pid = Pid::from_raw(child.id() as i32);
+        // the child stops with a SIGTRAP once it has exec'd, only then its memory is the binary's
+        wait::waitpid(pid, None)?;
+        ptrace::setoptions(pid, ptrace::Options::PTRACE_O_TRACEEXEC)?;
+        let mut engine = Self {
+            breakpoints: HashMap::new(),
+            hw_breakpoints: Default::default(),
+            processes: HashMap::new(),
+            pending: None,
+            signal: None,
+            hit: None,
+        };
+        let process = engine.process(pid)?;
+        Ok((engine, process))
     }
 
     fn wait(&mut self) -> Result<DebuggerStatus<Process>> {
+        if let Some(status) = self.pending.take() {
+            return Ok(status);
+        }
         // XXX: the issue with seperating wait from cont and wait
         // is that step and cont must be followed by wait
         self.handle_wait(wait::waitpid(None, Some(WaitPidFlag::__WALL))?)
@@ -74,17 +207,98 @@ impl DebuggerEngine for PtraceEngine {
 }
 
 impl PtraceEngine {
+    /// Continues the process, stepping over the breakpoint it is stopped at if any
+    fn resume(&mut self, process: &mut Process, signal: Option<Signal>) -> Result<()> {
+        let pid = process.pid;
+        // a held back signal goes first, the new one waits for the next continue
+        let signal = match self.signal.take() {
+            Some(held) => {
+                self.signal = signal;
+                Some(held)
+            }
+            None => signal,
+        };
+        // the process was moved away from the breakpoint it hit
+        if let Some(address) = self.hit.take() {
+            if address != process.get_registers()?.rip {
+                if let Some(bp) = self.breakpoints.get_mut(&address) {
+                    bp.enable(process)?;
+                }
+            }
+        }
+        if let Some(bp) = self.get_breakpoint(process)? {
+            // the process can be moved onto a breakpoint it didn't hit
+            bp.disable(process)?;
+            ptrace::step(pid, None)?;
+            let _status = wait::waitpid(pid, None)?;
+            bp.enable(process)?;
+            // the stepped over instruction can trigger a watchpoint, report it on the next wait
+            for slot in debugreg::take_triggered(pid)? {
+                if let Some(bp) = &self.hw_breakpoints[slot] {
+                    self.pending = Some(DebuggerStatus::WatchpointHit(process.clone(), bp.address));
+                    // the process is still stopped, the signal is delivered once it continues
+                    self.signal = signal;
+                    return Ok(());
+                }
+            }
+        } else {
+            let mut regs = process.get_registers()?;
+            if self.hw_breakpoint_at(regs.rip).is_some() {
+                // execute the instruction instead of hitting its breakpoint again
+                regs.eflags |= debugreg::EFLAGS_RF;
+                process.set_registers(regs)?;
+            }
+        }
+        ptrace::cont(pid, signal)?;
+        Ok(())
+    }
+
     pub fn get_breakpoint(&mut self, process: &Process) -> Result<Option<&mut Breakpoint>> {
         let regs = process.get_registers()?;
         Ok(self.breakpoints.get_mut(&regs.rip))
     }
 
+    fn has_breakpoint(&self, address: u64) -> bool {
+        self.breakpoints.contains_key(&address) || self.hw_breakpoint_at(address).is_some()
+    }
+
+    fn hw_breakpoint_at(&self, address: u64) -> Option<&HardwareBreakpoint> {
+        self.hw_breakpoints
+            .iter()
+            .flatten()
+            .find(|bp| bp.trigger == Trigger::Execute && bp.address == address)
+    }
+
+    /// Returns the process with the given pid, opening its memory the first time
+    fn process(&mut self, pid: Pid) -> Result<Process> {
+        if let Some(process) = self.processes.get(&pid) {
+            return Ok(process.clone());
+        }
+        let process = Process {
+            pid,
+            mem: Rc::new(RefCell::new(Process::open_mem(pid)?)),
+        };
+        self.processes.insert(pid, process.clone());
+        Ok(process)
+    }
+
     pub fn handle_wait(&mut self, status: WaitStatus) -> Result<DebuggerStatus<Process>> {
         use nix::sys::signal::Signal::*;
         match status {
             WaitStatus::Stopped(pid, SIGTRAP) => {
                 // debug!(?status);
-                let mut process = Process(pid);
+                let mut process = self.process(pid)?;
+                // instruction breakpoints trap before the instruction, with RIP pointing at it
+                for slot in debugreg::take_triggered(pid)? {
+                    match &self.hw_breakpoints[slot] {
+                        Some(bp) if bp.trigger == Trigger::Execute => {
+                            return Ok(DebuggerStatus::BreakpointHit(process, bp.address));
+                        }
+                        // data breakpoints trap after the access
+                        Some(bp) => return Ok(DebuggerStatus::WatchpointHit(process, bp.address)),
+                        None => {}
+                    }
+                }
                 let mut regs = process.get_registers()?;
                 let bp_addr = regs.rip - Breakpoint::instr_len();
 
@@ -92,18 +306,39 @@ impl PtraceEngine {
                     bp.disable(&mut process)?;
                     regs.rip -= Breakpoint::instr_len();
                     process.set_registers(regs)?;
+                    self.hit = Some(bp_addr);
                     return Ok(DebuggerStatus::BreakpointHit(process, bp_addr));
                 }
                 debug!(?regs, "did not find a breakpoint, still got a sigtrap");
                 Ok(DebuggerStatus::Stopped(process))
             }
-            WaitStatus::Stopped(pid, SIGSEGV) => {
+            WaitStatus::Stopped(pid, signal) => {
                 debug!(?status);
-                Ok(DebuggerStatus::Stopped(Process(pid)))
+                Ok(DebuggerStatus::Signaled(self.process(pid)?, signal))
+            }
+            WaitStatus::PtraceEvent(pid, SIGTRAP, nix::libc::PTRACE_EVENT_EXEC) => {
+                debug!(?status, "process exec'd");
+                // the old memory handle refers to the replaced address space
+                let process = self.process(pid)?;
+                *process.mem.borrow_mut() = Process::open_mem(pid)?;
+                // the breakpoints went away with the old image
+                self.breakpoints.clear();
+                self.hw_breakpoints = Default::default();
+                Ok(DebuggerStatus::Unknown)
             }
             WaitStatus::Exited(pid, exit_code) => {
                 debug!("process with pid {} exited with code {}", pid, exit_code);
-                Ok(DebuggerStatus::Exited(Process(pid), exit_code))
+                match self.processes.remove(&pid) {
+                    Some(process) => Ok(DebuggerStatus::Exited(process, exit_code)),
+                    None => Ok(DebuggerStatus::Unknown),
+                }
+            }
+            WaitStatus::Signaled(pid, signal, _) => {
+                debug!("process with pid {} was killed by {}", pid, signal);
+                match self.processes.remove(&pid) {
+                    Some(process) => Ok(DebuggerStatus::Exited(process, 128 + signal as i32)),
+                    None => Ok(DebuggerStatus::Unknown),
+                }
             }
             _ => {
                 debug!(?status);
@@ -121,18 +356,25 @@ impl PtraceEngine {
     }
 }
 
-#[derive(Debug)]
-pub struct Process(pub Pid);
+#[derive(Debug, Clone)]
+pub struct Process {
+    pub pid: Pid,
+    /// `/proc/<pid>/mem`, shared by the clones of the process and reopened after an exec
+    mem: Rc<RefCell<File>>,
+}
 
 impl Process {
-    fn proc_mem_path(&self) -> String {
-        format!("/proc/{}/mem", self.0)
+    fn open_mem(pid: Pid) -> std::io::Result<File> {
+        std::fs::OpenOptions::new()
+            .write(true)
+            .read(true)
+            .open(format!("/proc/{}/mem", pid))
     }
     fn proc_cmdline_path(&self) -> String {
-        format!("/proc/{}/cmdline", self.0)
+        format!("/proc/{}/cmdline", self.pid)
     }
     fn proc_vmmaps(&self) -> String {
-        format!("/proc/{}/maps", self.0)
+        format!("/proc/{}/maps", self.pid)
     }
 
     #[tracing::instrument]
@@ -175,11 +417,26 @@ impl ProcessInfo for Process {
     }
 
     fn get_registers(&self) -> Result<Registers> {
-        ptrace::getregs(self.0).map_err(|err| err.into())
+        ptrace::getregs(self.pid).map_err(|err| err.into())
     }
 
     fn set_registers(&self, regs: Registers) -> Result<()> {
-        ptrace::setregs(self.0, regs).map_err(|err| err.into())
+        ptrace::setregs(self.pid, regs).map_err(|err| err.into())
+    }
+
+    fn get_fp_registers(&self) -> Result<FpRegisters> {
+        // nix has no wrapper for PTRACE_GETFPREGS
+        let mut regs = std::mem::MaybeUninit::<FpRegisters>::uninit();
+        let res = unsafe {
+            nix::libc::ptrace(
+                nix::libc::PTRACE_GETFPREGS,
+                self.pid.as_raw(),
+                std::ptr::null_mut::<nix::libc::c_void>(),
+                regs.as_mut_ptr(),
+            )
+        };
+        nix::errno::Errno::result(res)?;
+        Ok(unsafe { regs.assume_init() })
     }
 
     fn get_memory_maps(&self) -> Result<Vec<MemoryRegion>> {
@@ -190,14 +447,37 @@ impl ProcessInfo for Process {
     }
 
     fn read_at(&self, address: u64, data: &mut [u8]) -> std::io::Result<usize> {
-        std::fs::File::open(self.proc_mem_path())?.read_at(data, address)
+        self.mem.borrow().read_at(data, address)
+    }
+
+    fn read_many(&self, reads: &mut [(u64, &mut [u8])]) -> std::io::Result<()> {
+        let total: usize = reads.iter().map(|(_, data)| data.len()).sum();
+        let remote: Vec<_> = reads
+            .iter()
+            .map(|(address, data)| RemoteIoVec {
+                base: *address as usize,
+                len: data.len(),
+            })
+            .collect();
+        let local: Vec<_> = reads
+            .iter_mut()
+            .map(|(_, data)| IoVec::from_mut_slice(&mut data[..]))
+            .collect();
+        if matches!(process_vm_readv(self.pid, &local, &remote), Ok(read) if read == total) {
+            return Ok(());
+        }
+        // process_vm_readv stops at the first unreadable page, which /proc/pid/mem can still read
+        for (address, data) in reads.iter_mut() {
+            self.mem.borrow().read_exact_at(data, *address)?;
+        }
+        Ok(())
     }
 
     fn write_at(&mut self, address: u64, data: &[u8]) -> std::io::Result<usize> {
-        std::fs::OpenOptions::new()
-            .write(true)
-            .read(true)
-            .open(self.proc_mem_path())?
-            .write_at(data, address)
+        self.mem.borrow().write_at(data, address)
+    }
+
+    fn boxed(&self) -> Box<dyn ProcessInfo> {
+        Box::new(self.clone())
     }
 }
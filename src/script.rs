@@ -0,0 +1,275 @@
+//! `--script`, Rhai scripts run at the reported calls
+//!
+//! A script defines any of these functions, `this` is a map kept between the calls:
+//!
+//! ```text
+//! fn on_enter(func) { ... }       // func: #{ name, address, depth, tail_call, args, stack }
+//! fn on_exit(func, ret) { ... }   // ret: the formatted return value, or () if unknown
+//! fn on_finish() { ... }          // once the process exited
+//! ```
+//!
+//! `on_enter` and `on_exit` can return `"skip"`, `"detach"` or `"kill"` to act on the trace,
+//! see [`Action`]. While they run the script can use `reg(name)`, `param(name or index)`,
+//! `read(addr, len)`, `read_u64(addr)` and `read_str(addr)`, `print` goes to the trace.
+
+use std::cell::{Cell, RefCell};
+use std::io::Write;
+use std::path::Path;
+use std::rc::Rc;
+
+use rhai::{Array, Blob, CallFnOptions, Dynamic, Engine, EvalAltResult, Map, Scope, AST};
+
+use crate::defs::{ProcessInfo, Registers, Result};
+use crate::error::Error;
+use crate::function::Function;
+use crate::handler::{Action, SharedWriter, TraceContext, TraceHandler};
+use crate::limits::Limit;
+use crate::process_ext::ProcessExt;
+
+/// Longest string returned by `read_str`
+const MAX_STRING_LEN: usize = 4096;
+
+type ScriptResult<T> = std::result::Result<T, Box<EvalAltResult>>;
+
+pub struct Script {
+    engine: Engine,
+    ast: AST,
+    scope: Scope<'static>,
+    /// `this` of the hooks
+    state: Dynamic,
+    current: Current,
+}
+
+/// What the script sees of the call a hook runs for
+struct Snapshot {
+    /// a handle on the stopped process, to read its memory
+    process: Box<dyn ProcessInfo>,
+    /// the registers when the hook started
+    registers: Registers,
+    /// the names and raw values of the parameters, in order
+    params: Vec<(Option<String>, std::result::Result<u64, String>)>,
+}
+
+impl Snapshot {
+    fn take(ctx: &TraceContext, function: &Function) -> Result<Self> {
+        let registers = ctx.process.get_registers()?;
+        let params = function
+            .parameters
+            .iter()
+            .map(|param| match param {
+                Ok(param) => {
+                    let value = ctx.process.get_param_value(&registers, param, ctx.cfa);
+                    (param.name.clone(), value.map_err(|err| err.to_string()))
+                }
+                Err(err) => (None, Err(format!("{:?}", err))),
+            })
+            .collect();
+        Ok(Self {
+            process: ctx.process.boxed(),
+            registers,
+            params,
+        })
+    }
+}
+
+/// The call a hook runs for, only set while the hook runs
+#[derive(Clone, Default)]
+struct Current {
+    call: Rc<RefCell<Option<Snapshot>>>,
+    depth: Rc<Cell<usize>>,
+}
+
+impl Current {
+    /// Runs `f` with a snapshot of the call available to the script
+    fn set<R>(
+        &self,
+        ctx: &TraceContext,
+        function: &Function,
+        f: impl FnOnce() -> Result<R>,
+    ) -> Result<R> {
+        *self.call.borrow_mut() = Some(Snapshot::take(ctx, function)?);
+        self.depth.set(ctx.depth);
+        let result = f();
+        self.call.borrow_mut().take();
+        result
+    }
+
+    fn with<R>(&self, f: impl FnOnce(&Snapshot) -> Result<R>) -> ScriptResult<R> {
+        let call = self.call.borrow();
+        let call = call
+            .as_ref()
+            .ok_or("the process can only be accessed in on_enter and on_exit")?;
+        f(call).map_err(|err| err.to_string().into())
+    }
+}
+
+impl Script {
+    /// Compiles the script and runs its top level statements, `print` writes to `out`
+    pub(crate) fn load(path: &Path, out: SharedWriter) -> Result<Self> {
+        let current = Current::default();
+        let mut engine = Engine::new();
+        register(&mut engine, &current, out);
+
+        let ast = engine.compile_file(path.into())?;
+        let mut scope = Scope::new();
+        engine.run_ast_with_scope(&mut scope, &ast)?;
+        Ok(Self {
+            engine,
+            ast,
+            scope,
+            state: Dynamic::from_map(Map::new()),
+            current,
+        })
+    }
+
+    /// Calls the function `name` of the script if it has one
+    fn call(&mut self, name: &str, args: Vec<Dynamic>) -> Result<Action> {
+        let defined = self
+            .ast
+            .iter_functions()
+            .any(|f| f.name == name && f.params.len() == args.len());
+        if !defined {
+            return Ok(Action::Continue);
+        }
+        let options = CallFnOptions::new()
+            .eval_ast(false)
+            .bind_this_ptr(&mut self.state);
+        let result: Dynamic =
+            self.engine
+                .call_fn_with_options(options, &mut self.scope, &self.ast, name, args)?;
+        match result.into_string() {
+            Ok(action) => match action.as_str() {
+                "continue" => Ok(Action::Continue),
+                "skip" => Ok(Action::Skip),
+                "detach" => Ok(Action::Detach),
+                "kill" => Ok(Action::Kill),
+                _ => Err(Error::UnknownAction(action)),
+            },
+            // hooks which return nothing continue
+            Err(_) => Ok(Action::Continue),
+        }
+    }
+}
+
+impl TraceHandler for Script {
+    fn on_enter(
+        &mut self,
+        ctx: &TraceContext,
+        function: &Function,
+        args: &[String],
+    ) -> Result<Action> {
+        let call = call_map(ctx, function, args);
+        let current = self.current.clone();
+        current.set(ctx, function, || self.call("on_enter", vec![call]))
+    }
+
+    fn on_exit(
+        &mut self,
+        ctx: &TraceContext,
+        function: &Function,
+        ret: Option<&str>,
+    ) -> Result<Action> {
+        let call = call_map(ctx, function, &[]);
+        let ret = ret.map_or(Dynamic::UNIT, |ret| ret.into());
+        let current = self.current.clone();
+        current.set(ctx, function, || self.call("on_exit", vec![call, ret]))
+    }
+
+    fn on_finish(&mut self, _limited: &[(&Function, u64, Limit)]) -> Result<()> {
+        self.current.depth.set(0);
+        self.call("on_finish", vec![])?;
+        Ok(())
+    }
+}
+
+/// The `func` argument of the hooks
+fn call_map(ctx: &TraceContext, function: &Function, args: &[String]) -> Dynamic {
+    let mut call = Map::new();
+    call.insert("name".into(), function.name.clone().into());
+    call.insert("address".into(), (function.address as i64).into());
+    call.insert("depth".into(), (ctx.depth as i64).into());
+    call.insert("tail_call".into(), ctx.tail_call.into());
+    let args: Array = args.iter().map(|arg| arg.clone().into()).collect();
+    call.insert("args".into(), args.into());
+    let stack: Array = ctx
+        .callers
+        .iter()
+        .map(|name| name.to_string().into())
+        .collect();
+    call.insert("stack".into(), stack.into());
+    call.into()
+}
+
+/// Adds the functions reading the process and sends `print` to the trace
+fn register(engine: &mut Engine, current: &Current, out: SharedWriter) {
+    let depth = current.depth.clone();
+    engine.on_print(move |text| {
+        // print has no way to report a failed write
+        let _ = writeln!(
+            out.0.borrow_mut(),
+            "{}> {}",
+            str::repeat("| ", depth.get() + 1),
+            text
+        );
+    });
+
+    let cur = current.clone();
+    engine.register_fn("reg", move |name: &str| -> ScriptResult<i64> {
+        let register = gimli::X86_64::name_to_register(name)
+            .ok_or_else(|| format!("no register named {}", name))?;
+        cur.with(|call| Ok(call.process.get_register_value(&call.registers, register)? as i64))
+    });
+
+    let cur = current.clone();
+    engine.register_fn("param", move |name: &str| -> ScriptResult<Dynamic> {
+        let value = cur.with(|call| {
+            let param = call
+                .params
+                .iter()
+                .find(|(param, _)| param.as_deref() == Some(name));
+            Ok(param.map(|(_, value)| value.clone()))
+        })?;
+        param_value(value)
+    });
+    let cur = current.clone();
+    engine.register_fn("param", move |index: i64| -> ScriptResult<Dynamic> {
+        let value = cur.with(|call| {
+            let param = usize::try_from(index)
+                .ok()
+                .and_then(|index| call.params.get(index));
+            Ok(param.map(|(_, value)| value.clone()))
+        })?;
+        param_value(value)
+    });
+
+    let cur = current.clone();
+    engine.register_fn(
+        "read",
+        move |address: i64, len: i64| -> ScriptResult<Blob> {
+            cur.with(|call| {
+                let mut data = vec![0; len.max(0) as usize];
+                let read = call.process.read_at(address as u64, &mut data)?;
+                data.truncate(read);
+                Ok(data)
+            })
+        },
+    );
+
+    let cur = current.clone();
+    engine.register_fn("read_u64", move |address: i64| -> ScriptResult<i64> {
+        cur.with(|call| Ok(call.process.read_u64_at(address as u64)? as i64))
+    });
+
+    let cur = current.clone();
+    engine.register_fn("read_str", move |address: i64| -> ScriptResult<String> {
+        cur.with(|call| call.process.read_c_string(address as u64, MAX_STRING_LEN))
+    });
+}
+
+/// The raw value of a parameter, `()` if the function has no such parameter
+fn param_value(value: Option<std::result::Result<u64, String>>) -> ScriptResult<Dynamic> {
+    match value {
+        Some(value) => Ok((value? as i64).into()),
+        None => Ok(Dynamic::UNIT),
+    }
+}
@@ -0,0 +1,1250 @@
+//! The [`Tracer`] builder, which runs a binary and reports the calls of its functions
+
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsString;
+use std::fmt::Debug;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::rc::Rc;
+
+use cpp_demangle::Symbol;
+use object::Object;
+use regex::Regex;
+use tracing::{debug, warn};
+
+use crate::backtrace::Backtracer;
+use crate::cache;
+use crate::cli::{FuncSource, WatchSpec};
+use crate::condition::Condition;
+use crate::defs::{DebuggerEngine, DebuggerStatus, ProcessInfo, Result};
+use crate::error::Error;
+use crate::filter::{runtime_functions, FunctionFilter};
+use crate::function::{
+    annotate_sources_dwarf, call_graph, discover_functions, get_functions, get_functions_auto,
+    get_functions_dwarf, get_inlined_functions_dwarf, FormalParameterKind, Function,
+};
+use crate::handler::{
+    Action, Event, EventCallback, Printer, SharedWriter, TraceContext, TraceHandler,
+};
+use crate::inject::{return_early, Fault, Injection, Injector};
+use crate::lazy::LazyPlacer;
+use crate::limits::CallLimits;
+use crate::lines::{LineTable, Sources};
+use crate::process_ext::ProcessExt;
+use crate::ptrace_engine::PtraceEngine;
+use crate::script::Script;
+use crate::utils::get_base_region;
+use crate::watch::Watch;
+
+/// Runs a binary and traces the calls of its functions
+///
+/// ```no_run
+/// # fn main() -> ftrace_rs::Result<()> {
+/// ftrace_rs::Tracer::new("./fact")
+///     .arg("10")
+///     .max_depth(3)
+///     .on_event(|event| println!("{:?}", event))
+///     .run()
+/// # }
+/// ```
+pub struct Tracer {
+    binary: PathBuf,
+    args: Vec<OsString>,
+    source: FuncSource,
+    inline: bool,
+    cache: bool,
+    lazy: bool,
+    filter: FunctionFilter,
+    from_main: bool,
+    hw: Option<Regex>,
+    watches: Vec<WatchSpec>,
+    watch_reads: bool,
+    injections: Vec<Injection>,
+    prologue: bool,
+    backtrace: Option<Regex>,
+    locations: bool,
+    snippets: bool,
+    output: Output,
+    max_calls: Option<u64>,
+    max_rate: Option<u64>,
+    sink: Box<dyn Write>,
+    script: Option<PathBuf>,
+    handlers: Vec<Box<dyn TraceHandler>>,
+}
+
+impl Tracer {
+    /// Traces `binary` with the same defaults as the command line, printing to stdout
+    pub fn new(binary: impl Into<PathBuf>) -> Self {
+        Self {
+            binary: binary.into(),
+            args: vec![],
+            source: FuncSource::Heuristic,
+            inline: false,
+            cache: true,
+            lazy: false,
+            filter: FunctionFilter {
+                runtime: Some(runtime_functions()),
+                ..Default::default()
+            },
+            from_main: false,
+            hw: None,
+            watches: vec![],
+            watch_reads: false,
+            injections: vec![],
+            prologue: true,
+            backtrace: None,
+            locations: false,
+            snippets: false,
+            output: Output {
+                conditions: vec![],
+                cond_subtree: false,
+                max_depth: None,
+                focus: None,
+                main: None,
+            },
+            max_calls: None,
+            max_rate: None,
+            sink: Box::new(io::stdout()),
+            script: None,
+            handlers: vec![],
+        }
+    }
+
+    /// Adds an argument passed to the binary
+    pub fn arg(mut self, arg: impl Into<OsString>) -> Self {
+        self.args.push(arg.into());
+        self
+    }
+
+    pub fn args<I: IntoIterator<Item = S>, S: Into<OsString>>(mut self, args: I) -> Self {
+        self.args.extend(args.into_iter().map(Into::into));
+        self
+    }
+
+    /// How to resolve the functions in the binary
+    pub fn source(mut self, source: FuncSource) -> Self {
+        self.source = source;
+        self
+    }
+
+    /// Also trace inlined instances of functions (needs DWARF)
+    pub fn inline(mut self, inline: bool) -> Self {
+        self.inline = inline;
+        self
+    }
+
+    /// Whether the functions found are cached between runs, on by default
+    pub fn cache(mut self, cache: bool) -> Self {
+        self.cache = cache;
+        self
+    }
+
+    /// Only set breakpoints on functions once they become reachable through direct calls
+    pub fn lazy(mut self, lazy: bool) -> Self {
+        self.lazy = lazy;
+        self
+    }
+
+    /// Which functions get traced, replaces the default which skips the runtime functions
+    pub fn filter(mut self, filter: FunctionFilter) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    /// Only report the calls made while `main` runs
+    pub fn from_main(mut self, from_main: bool) -> Self {
+        self.from_main = from_main;
+        self
+    }
+
+    /// Trace the matching functions with hardware breakpoints
+    pub fn hardware_breakpoints(mut self, functions: Regex) -> Self {
+        self.hw = Some(functions);
+        self
+    }
+
+    /// Also break at the end of the prologue of functions with parameters relative to the frame
+    /// base, on by default. The calls are captured at the entry of the functions either way,
+    /// without it those parameters are read before the prologue stores them
+    pub fn prologue(mut self, prologue: bool) -> Self {
+        self.prologue = prologue;
+        self
+    }
+
+    /// Reports the writes to a variable or address
+    pub fn watch(mut self, watch: WatchSpec) -> Self {
+        self.watches.push(watch);
+        self
+    }
+
+    /// Also report the reads of the watched variables
+    pub fn watch_reads(mut self, watch_reads: bool) -> Self {
+        self.watch_reads = watch_reads;
+        self
+    }
+
+    /// Rewrites the arguments or the return value of the calls to a function, which must be
+    /// traced: [`Tracer::run`] fails otherwise
+    pub fn inject(mut self, injection: Injection) -> Self {
+        self.injections.push(injection);
+        self
+    }
+
+    /// Print the call stack under the calls to matching functions
+    pub fn backtrace(mut self, functions: Regex) -> Self {
+        self.backtrace = Some(functions);
+        self
+    }
+
+    /// Print where each call comes from and where the function is declared (needs DWARF)
+    pub fn locations(mut self, locations: bool) -> Self {
+        self.locations = locations;
+        self
+    }
+
+    /// Also print the source line of each call, implies [`Tracer::locations`]
+    pub fn snippets(mut self, snippets: bool) -> Self {
+        self.snippets = snippets;
+        self
+    }
+
+    /// Only report the calls of a function whose arguments match the condition, the function
+    /// must be traced like for [`Tracer::inject`]
+    pub fn condition(mut self, condition: Condition) -> Self {
+        self.output.conditions.push(condition);
+        self
+    }
+
+    /// Only report the calls which match a condition and everything they call
+    pub fn cond_subtree(mut self, cond_subtree: bool) -> Self {
+        self.output.cond_subtree = cond_subtree;
+        self
+    }
+
+    /// Don't report calls nested deeper than this, counted from the focused call if any
+    pub fn max_depth(mut self, max_depth: usize) -> Self {
+        self.output.max_depth = Some(max_depth);
+        self
+    }
+
+    /// Only report the calls to matching functions and everything they call
+    pub fn focus(mut self, focus: Regex) -> Self {
+        self.output.focus = Some(focus);
+        self
+    }
+
+    /// Stop tracing a function after this many calls
+    pub fn max_calls(mut self, max_calls: u64) -> Self {
+        self.max_calls = Some(max_calls);
+        self
+    }
+
+    /// Stop tracing a function once it is called more than this many times per second
+    pub fn max_rate(mut self, max_rate: u64) -> Self {
+        self.max_rate = Some(max_rate);
+        self
+    }
+
+    /// Where the trace is printed, use [`io::sink`] to only get the events
+    pub fn output(mut self, sink: impl Write + 'static) -> Self {
+        self.sink = Box::new(sink);
+        self
+    }
+
+    /// Calls `callback` with every event, after it is printed
+    pub fn on_event(mut self, callback: impl FnMut(&Event) + 'static) -> Self {
+        self.handlers
+            .push(Box::new(EventCallback(Box::new(callback))));
+        self
+    }
+
+    /// Runs a Rhai script at the reported calls, after the other handlers. Its `print`s go to
+    /// the [`Tracer::output`], see [`script`](crate::script) for its hooks
+    pub fn script(mut self, path: impl Into<PathBuf>) -> Self {
+        self.script = Some(path.into());
+        self
+    }
+
+    /// Adds a handler whose hooks are called after the trace is printed
+    pub fn handler(mut self, handler: impl TraceHandler + 'static) -> Self {
+        self.handlers.push(Box::new(handler));
+        self
+    }
+
+    /// Runs the binary with ptrace until it exits
+    pub fn run(self) -> Result<()> {
+        self.run_with::<PtraceEngine>()
+    }
+
+    /// Runs the binary with the given engine until it exits
+    pub fn run_with<E>(mut self) -> Result<()>
+    where
+        E: DebuggerEngine,
+        E::Process: ProcessInfo + Debug,
+    {
+        let binary = self.binary.clone();
+        let binary = binary.as_path();
+
+        let bin_data = std::fs::read(binary)?;
+        let obj_file = object::File::parse(&*bin_data)?;
+
+        let binary_is_relocatable = matches!(
+            obj_file.kind(),
+            object::ObjectKind::Dynamic | object::ObjectKind::Relocatable
+        );
+
+        // only the functions of the binary itself are traced, not those of its shared libraries
+        let object = binary.canonicalize()?.to_string_lossy().into_owned();
+        let objects = &self.filter.objects;
+        if !objects.is_empty() && !objects.iter().any(|glob| glob.is_match(&object)) {
+            return Err(Error::ObjectFilter(object));
+        }
+
+        // injections which can't work are rejected before the process starts
+        let injector = Injector::new(std::mem::take(&mut self.injections), &obj_file)?;
+
+        let sink = std::mem::replace(&mut self.sink, Box::new(io::sink()));
+        let out = SharedWriter(Rc::new(RefCell::new(sink)));
+        // loaded before the process starts, its top level statements can already print
+        if let Some(script) = self.script.take() {
+            let script = Script::load(&script, out.clone())?;
+            self.handlers.push(Box::new(script));
+        }
+
+        let mut cmd = Command::new(binary);
+        cmd.args(&self.args);
+        let (mut engine, mut last_process) = E::spawn(cmd)?;
+
+        let maps = last_process.get_memory_maps()?;
+        let base_region = match get_base_region(&maps, &object) {
+            Some(region) => region,
+            None => {
+                engine.kill(&mut last_process)?;
+                return Err(Error::NotMapped(object));
+            }
+        };
+        debug!(?maps, ?binary_is_relocatable, ?base_region);
+        let cache_key = if self.cache {
+            let options = format!("{:?} inline={}", self.source, self.inline);
+            match cache::CacheKey::new(binary, &obj_file, options) {
+                Ok(key) => Some(key),
+                Err(err) => {
+                    warn!(
+                        ?err,
+                        "could not identify the binary, the functions are not cached"
+                    );
+                    None
+                }
+            }
+        } else {
+            None
+        };
+        let mut funcs = match cache_key.as_ref().and_then(cache::load) {
+            Some(funcs) => funcs,
+            None => {
+                let funcs = resolve_functions(self.source, self.inline, binary, &obj_file)?;
+                match cache_key {
+                    Some(key) => cache::store(key, funcs),
+                    None => funcs,
+                }
+            }
+        };
+        debug!(?funcs);
+
+        let base = if binary_is_relocatable {
+            base_region.start
+        } else {
+            0
+        };
+        let graph = if self.lazy {
+            let addresses: Vec<_> = funcs
+                .iter()
+                .filter(|f| !f.inlined)
+                .map(|f| f.address)
+                .collect();
+            let graph = call_graph(&obj_file, &addresses)
+                .into_iter()
+                .map(|(func, callees)| (func + base, callees.iter().map(|c| c + base).collect()))
+                .collect();
+            Some(graph)
+        } else {
+            None
+        };
+
+        for func in funcs.iter_mut() {
+            if binary_is_relocatable {
+                func.prologue_end_addr = func.prologue_end_addr.map(|x| x + base_region.start);
+                func.address += base_region.start;
+                for exit in func.exit_addrs.iter_mut() {
+                    *exit += base_region.start;
+                }
+            }
+            func.name = match Symbol::new(&func.name).map(|op| op.to_string()) {
+                Ok(name) => name,
+                // rustc demangle will return the original if it cant parser
+                Err(_) => rustc_demangle::demangle(&func.name).to_string(),
+            };
+        }
+        debug!(?funcs);
+        let lines = if self.backtrace.is_some() || self.locations || self.snippets {
+            Rc::new(LineTable::load(&obj_file)?)
+        } else {
+            Rc::default()
+        };
+        // backtraces are symbolized with every function, traced or not
+        let backtrace = match self.backtrace.take() {
+            Some(functions) => {
+                let backtracer = Backtracer::new(&obj_file, base, &funcs, lines.clone())?;
+                Some((functions, backtracer))
+            }
+            None => None,
+        };
+        let sources =
+            (self.locations || self.snippets).then(|| Sources::new(lines, base, self.snippets));
+        // main is usually called through a pointer by the libc, so it is a root of its own
+        let mut seeds = vec![obj_file.entry() + base];
+        seeds.extend(funcs.iter().filter(|f| f.name == "main").map(|f| f.address));
+
+        // filter functions
+        let keep = |f: &Function| self.filter.keep(f, &object, f.address - base);
+        // with --from-main, main is traced even if filtered out, to know when it runs
+        let main = if self.from_main {
+            let main = funcs.iter().find(|f| f.name == "main" && !f.inlined);
+            if main.is_none() {
+                warn!("no main function found, tracing everything");
+            }
+            main.map(|main| (main.address, keep(main)))
+        } else {
+            None
+        };
+        funcs.retain(|f| keep(f) || matches!(main, Some((address, _)) if address == f.address));
+        self.output.main = main;
+
+        // injections and conditions on functions which aren't traced would never apply,
+        // injections only apply to calls and conditions to inlined instances too
+        let traced = |name: &str, inlined: bool| {
+            funcs
+                .iter()
+                .any(|f| f.name == name && (inlined || !f.inlined))
+        };
+        let untraced = injector
+            .functions()
+            .find(|name| !traced(name, false))
+            .or_else(|| {
+                let conditions = self.output.conditions.iter();
+                conditions
+                    .map(|condition| condition.function.as_str())
+                    .find(|name| !traced(name, true))
+            });
+        if let Some(name) = untraced {
+            let name = name.to_owned();
+            engine.kill(&mut last_process)?;
+            return Err(Error::UntracedFunction(name));
+        }
+
+        let lazy = graph.map(|graph| {
+            let bp_addrs = funcs
+                .iter()
+                .filter(|f| !f.inlined)
+                .map(|f| (f.address, breakpoints(f, self.prologue)))
+                .collect();
+            (LazyPlacer::new(graph, bp_addrs), seeds)
+        });
+
+        let hw = match &self.hw {
+            Some(hw) => funcs
+                .iter()
+                .filter(|f| !f.inlined && hw.is_match(&f.name))
+                .flat_map(|f| breakpoints(f, self.prologue))
+                .collect(),
+            None => HashSet::new(),
+        };
+        let placement = Placement {
+            lazy,
+            hw,
+            from_main: None,
+        };
+
+        // watchpoints go first, as hardware breakpoints can fall back to software ones
+        let mut watches = vec![];
+        for spec in self.watches.iter() {
+            let mut watch = Watch::resolve(spec, &obj_file, base)?;
+            watch.arm(&mut engine, &mut last_process, self.watch_reads)?;
+            watches.push(watch);
+        }
+
+        let printer = Printer {
+            out,
+            backtrace,
+            sources,
+        };
+        self.handlers.insert(0, Box::new(printer));
+        self.trace(engine, last_process, funcs, placement, watches, injector)
+    }
+
+    fn trace<E>(
+        &mut self,
+        mut engine: E,
+        mut last_process: E::Process,
+        funcs: Vec<Function>,
+        mut placement: Placement,
+        mut watches: Vec<Watch>,
+        mut injector: Injector,
+    ) -> Result<()>
+    where
+        E: DebuggerEngine,
+        E::Process: ProcessInfo + Debug,
+    {
+        let mut funcs_map = HashMap::new();
+        let mut funcs_prologue_map = HashMap::new();
+        let mut inline_map: HashMap<u64, Vec<Function>> = HashMap::new();
+        let mut bp_addrs = vec![];
+        let mut limits = CallLimits::new(self.max_calls, self.max_rate);
+
+        // the entries and exits of the inlined instances
+        let mut inline_bps: HashSet<u64> = HashSet::new();
+
+        for func in funcs.into_iter() {
+            if func.inlined {
+                inline_bps.insert(func.address);
+                inline_bps.extend(func.exit_addrs.iter().copied());
+                // inlined instances are not in the call graph, they are always placed
+                bp_addrs.push(func.address);
+                bp_addrs.extend(&func.exit_addrs);
+                // nested instances can start at the same address, outermost first
+                inline_map.entry(func.address).or_default().push(func);
+                continue;
+            }
+            if let Some(prologue_end) = prologue_breakpoint(&func, self.prologue) {
+                funcs_prologue_map.insert(prologue_end, func.address);
+            }
+            if placement.lazy.is_none() {
+                bp_addrs.extend(breakpoints(&func, self.prologue));
+            }
+            funcs_map.insert(func.address, func);
+        }
+        let main = self
+            .output
+            .main
+            .and_then(|(address, _)| funcs_map.get(&address));
+        if let Some(main) = main {
+            // only main gets breakpoints until it runs, the others are placed when it is entered
+            let main_bps = breakpoints(main, self.prologue);
+            bp_addrs.retain(|bp| !main_bps.contains(bp));
+            let held = std::mem::replace(&mut bp_addrs, main_bps.clone());
+            placement.from_main = Some(FromMain {
+                main: main_bps,
+                held,
+                placed: vec![],
+                armed: false,
+            });
+        } else if let Some((placer, seeds)) = placement.lazy.as_mut() {
+            bp_addrs.extend(placer.reachable(seeds));
+        }
+        debug!(count = bp_addrs.len(), "setting breakpoints");
+        placement.place(&mut engine, &mut last_process, &bp_addrs)?;
+        engine.cont(&mut last_process)?;
+        // functions which have been entered but not yet returned from
+        let mut stack: Vec<Frame> = vec![];
+        // a function entered whose call is reported at the end of its prologue, with the
+        // return address and canonical frame address found at the entry
+        let mut pending: Option<(u64, Option<u64>, u64)> = None;
+
+        // TODO: this wait and cont thingy is kinda falky
+        loop {
+            let status = engine.wait()?;
+            debug!(?status);
+            let mut action = Action::Continue;
+            let mut signal = None;
+            match status {
+                DebuggerStatus::BreakpointHit(process, address) => {
+                    last_process = process;
+                    // the end of an inlined instance can coincide with the start of something else
+                    while matches!(stack.last(), Some(f) if f.func.inlined && f.func.exit_addrs.contains(&address))
+                    {
+                        stack.pop();
+                    }
+                    // an inline exit is also hit when its instance was skipped, as at the join
+                    // point after a conditional inlined call, it and an inline entry only return
+                    // from a call which returns there
+                    let returns = !inline_bps.contains(&address)
+                        || stack.iter().any(|f| f.ret_addr == Some(address));
+
+                    if let (Some(func), Some((placer, _))) =
+                        (funcs_map.get(&address), placement.lazy.as_mut())
+                    {
+                        let callees = placer.callees_of(func.address);
+                        placement.place(&mut engine, &mut last_process, &callees)?;
+                    }
+
+                    // the call entered, its return address and its canonical frame address
+                    let mut call = None;
+                    let mut is_entry = false;
+                    if let Some(func) = funcs_map.get(&address) {
+                        // at the entry the return address is on top of the stack, whether or
+                        // not the function sets up a frame pointer
+                        let registers = last_process.get_registers()?;
+                        let ret_addr = last_process.read_u64_at(registers.rsp)?;
+                        let ret_addr = (ret_addr > 1).then_some(ret_addr);
+                        let cfa = registers.rsp + 8;
+                        if prologue_breakpoint(func, self.prologue).is_some() {
+                            // reported once the prologue stored the parameters
+                            pending = Some((func.address, ret_addr, cfa));
+                        } else {
+                            call = Some((func, ret_addr, cfa));
+                        }
+                    } else if let Some(entry) = funcs_prologue_map.get(&address) {
+                        match pending.take() {
+                            Some((func, ret_addr, cfa)) if func == *entry => {
+                                call = Some((&funcs_map[entry], ret_addr, cfa));
+                            }
+                            _ => debug!(
+                                address,
+                                "prologue end reached without going through the entry"
+                            ),
+                        }
+                    } else if let Some(funcs) = inline_map.get(&address) {
+                        // an inlined instance can start right where a call returns
+                        if returns {
+                            action = self.leave(
+                                &mut engine,
+                                &mut last_process,
+                                address,
+                                &mut stack,
+                                &inline_bps,
+                            )?;
+                        }
+                        for func in funcs {
+                            let entered =
+                                self.enter(&last_process, func, None, None, false, &mut stack)?;
+                            action = action.max(entered);
+                        }
+                        is_entry = true;
+                    } else if returns {
+                        action = self.leave(
+                            &mut engine,
+                            &mut last_process,
+                            address,
+                            &mut stack,
+                            &inline_bps,
+                        )?;
+                    }
+
+                    // the arguments are rewritten before they are reported
+                    let mut skip = None;
+                    if let Some((func, ret_addr, cfa)) = call {
+                        if let Some(ret_addr) = ret_addr {
+                            engine.set_breakpoint(&mut last_process, ret_addr)?;
+                        }
+                        let tail_call = is_tail_call(&stack, ret_addr, cfa);
+                        if tail_call {
+                            // the inlined instances in the caller are over
+                            while matches!(stack.last(), Some(frame) if frame.func.inlined) {
+                                stack.pop();
+                            }
+                        }
+                        if matches!(self.output.main, Some((main, _)) if main == func.address) {
+                            placement.arm(&mut engine, &mut last_process)?;
+                        }
+                        let fault = injector.enter(&mut last_process, func, cfa)?;
+                        action = self.enter(
+                            &last_process,
+                            func,
+                            ret_addr,
+                            Some(cfa),
+                            tail_call,
+                            &mut stack,
+                        )?;
+                        if let Some(frame) = stack.last_mut() {
+                            frame.fault = fault;
+                        }
+                        if matches!(fault, Some(fault) if fault.skip) {
+                            skip = ret_addr.map(|ret_addr| (ret_addr, cfa));
+                        }
+                        is_entry = true;
+                    }
+
+                    // the call was entered, stop tracing it if it is too hot or a handler says so
+                    if let Some(frame) = stack.last().filter(|_| is_entry) {
+                        if let Some(limit) = limits.record(frame.func.address) {
+                            let func = frame.func;
+                            with_context(&last_process, &stack, false, |ctx| {
+                                self.dispatch(|h| {
+                                    h.on_limit(ctx, func, limit).map(|_| Action::Continue)
+                                })
+                            })?;
+                            for bp in breakpoints(func, self.prologue) {
+                                engine.remove_breakpoint(&mut last_process, bp)?;
+                            }
+                        } else if action == Action::Skip {
+                            for bp in breakpoints(frame.func, self.prologue) {
+                                engine.remove_breakpoint(&mut last_process, bp)?;
+                            }
+                        }
+                    }
+
+                    if let Some((ret_addr, cfa)) = skip {
+                        return_early(&last_process, cfa)?;
+                        let exit = self.leave(
+                            &mut engine,
+                            &mut last_process,
+                            ret_addr,
+                            &mut stack,
+                            &inline_bps,
+                        )?;
+                        action = action.max(exit);
+                    }
+
+                    let in_main = |main| stack.iter().any(|frame| frame.func.address == main);
+                    if matches!(self.output.main, Some((main, _)) if !in_main(main)) {
+                        placement.disarm(&mut engine, &mut last_process)?;
+                    }
+                }
+                DebuggerStatus::WatchpointHit(process, address) => {
+                    last_process = process;
+                    if let Some(watch) = watches.iter_mut().find(|w| w.covers(address)) {
+                        let (old, new) = watch.update(&last_process)?;
+                        action = with_context(&last_process, &stack, true, |ctx| {
+                            self.dispatch(|h| h.on_watch(ctx, &watch.name, &old, &new))
+                        })?;
+                    }
+                }
+                DebuggerStatus::Signaled(process, sig) => {
+                    last_process = process;
+                    action = with_context(&last_process, &stack, true, |ctx| {
+                        self.dispatch(|h| h.on_signal(ctx, sig))
+                    })?;
+                    if action != Action::Skip {
+                        signal = Some(sig);
+                    }
+                }
+                DebuggerStatus::Exited(_pid, exit_code) => {
+                    self.dispatch(|h| h.on_exit_process(exit_code).map(|_| Action::Continue))?;
+                    break;
+                }
+                DebuggerStatus::Stopped(pid) => {
+                    warn!(?pid, "got Stopped event");
+                    break;
+                }
+                _ => {}
+            }
+            match (action, signal) {
+                // the exit is reported by the next wait
+                (Action::Kill, _) => engine.kill(&mut last_process)?,
+                (Action::Detach, _) => {
+                    engine.detach(&mut last_process)?;
+                    break;
+                }
+                (_, Some(signal)) => engine.cont_with_signal(&mut last_process, signal)?,
+                (_, None) => engine.cont(&mut last_process)?,
+            }
+        }
+
+        let all_funcs: HashMap<_, _> = funcs_map
+            .values()
+            .chain(inline_map.values().flatten())
+            .map(|func| (func.address, func))
+            .collect();
+        let mut limited: Vec<_> = limits
+            .limited()
+            .filter_map(|(address, calls, limit)| Some((*all_funcs.get(&address)?, calls, limit)))
+            .collect();
+        limited.sort_by_key(|(func, _, _)| func.address);
+        self.dispatch(|h| h.on_finish(&limited).map(|_| Action::Continue))?;
+        Ok(())
+    }
+
+    /// Pops the call returning to `address`, reporting its return value if it was reported
+    fn leave<'a, E>(
+        &mut self,
+        engine: &mut E,
+        process: &mut E::Process,
+        address: u64,
+        stack: &mut Vec<Frame<'a>>,
+        inline_bps: &HashSet<u64>,
+    ) -> Result<Action>
+    where
+        E: DebuggerEngine,
+        E::Process: ProcessInfo,
+    {
+        let mut action = Action::Continue;
+        let end = stack.len() - returning_frames(stack);
+        while let Some(frame) = stack.last().filter(|_| stack.len() > end) {
+            // inlined instances left through an early return never hit their exits
+            if frame.func.inlined {
+                stack.pop();
+                continue;
+            }
+            if let Some(fault) = frame.fault {
+                fault.apply(process)?;
+            }
+            // TODO: this is the ret this should be better lol
+            if let Some(frame) = stack.last().filter(|frame| frame.shown) {
+                let func = frame.func;
+                let value = match &func.return_type {
+                    Some(ret) => Some(process.get_return_value(ret)?),
+                    None => None,
+                };
+                let exit = with_context(&*process, stack, false, |ctx| {
+                    self.dispatch(|h| h.on_exit(ctx, func, value.as_deref()))
+                })?;
+                if exit == Action::Skip {
+                    for bp in breakpoints(func, self.prologue) {
+                        engine.remove_breakpoint(process, bp)?;
+                    }
+                }
+                action = action.max(exit);
+            }
+            stack.pop();
+        }
+        // a stale return breakpoint would be taken for the return of whatever
+        // is on top of the stack when the code runs again
+        if !inline_bps.contains(&address) && !stack.iter().any(|f| f.ret_addr == Some(address)) {
+            engine.remove_breakpoint(process, address)?;
+        }
+        Ok(action)
+    }
+
+    /// Pushes the frame of a function which was just entered, reporting it unless filtered out.
+    /// `cfa` is the canonical frame address of the call, unknown for inlined instances, and
+    /// `tail_call` whether the function was jumped to by the call on top of the stack
+    fn enter<'a, P: ProcessInfo>(
+        &mut self,
+        process: &P,
+        func: &'a Function,
+        ret_addr: Option<u64>,
+        cfa: Option<u64>,
+        tail_call: bool,
+        stack: &mut Vec<Frame<'a>>,
+    ) -> Result<Action> {
+        let output = &self.output;
+        let mut matched = true;
+        let mut conditional = false;
+        for condition in output.conditions.iter().filter(|c| c.function == func.name) {
+            conditional = true;
+            matched = matched && condition.eval(process, func, cfa)?;
+        }
+        let focused = matches!(&output.focus, Some(focus) if focus.is_match(&func.name));
+        let is_main = matches!(output.main, Some((address, _)) if address == func.address);
+        if matches!(output.main, Some((_, false)) if is_main) {
+            // only traced to know when main runs
+            matched = false;
+        }
+        let root = (output.cond_subtree && conditional && matched) || focused || is_main;
+
+        let (inside, depth) = output.nesting(stack.last(), root);
+        let shown = matched && output.shows(inside, depth);
+
+        stack.push(Frame {
+            func,
+            ret_addr,
+            shown,
+            inside,
+            depth,
+            fault: None,
+            cfa,
+            tail_call,
+        });
+        if !shown {
+            return Ok(Action::Continue);
+        }
+        let args = process.get_fn_param_values(&func.parameters, cfa)?;
+        with_context(process, stack, false, |ctx| {
+            self.dispatch(|h| h.on_enter(ctx, func, &args))
+        })
+    }
+
+    /// Calls a hook of every handler, returning the strongest action
+    fn dispatch(
+        &mut self,
+        mut hook: impl FnMut(&mut dyn TraceHandler) -> Result<Action>,
+    ) -> Result<Action> {
+        let mut action = Action::Continue;
+        for handler in self.handlers.iter_mut() {
+            action = action.max(hook(handler.as_mut())?);
+        }
+        Ok(action)
+    }
+}
+
+/// Whether a call returning to `ret_addr` with its frame at `cfa` was jumped to by the end of
+/// the innermost traced call, which leaves its return address and stack frame to it
+fn is_tail_call(stack: &[Frame], ret_addr: Option<u64>, cfa: u64) -> bool {
+    let caller = stack.iter().rev().find(|frame| !frame.func.inlined);
+    ret_addr.is_some()
+        && matches!(caller, Some(frame) if frame.ret_addr == ret_addr && frame.cfa == Some(cfa))
+}
+
+/// How many frames on top of `stack` a return pops: the inlined instances left through an
+/// early return, the returning call and the calls it replaced through tail calls
+fn returning_frames(stack: &[Frame]) -> usize {
+    let mut count = 0;
+    for frame in stack.iter().rev() {
+        count += 1;
+        if !frame.func.inlined && !frame.tail_call {
+            break;
+        }
+    }
+    count
+}
+
+/// Runs `f` with the context of the call on top of the stack, `inner` for what happens inside
+/// of it such as a watchpoint hit or a signal, which is one level deeper
+fn with_context<R>(
+    process: &dyn ProcessInfo,
+    stack: &[Frame],
+    inner: bool,
+    f: impl FnOnce(&TraceContext) -> R,
+) -> R {
+    let callers: Vec<_> = stack.iter().map(|f| f.func.name.as_str()).collect();
+    let top = stack.last();
+    let depth = top.map_or(0, |frame| frame.depth);
+    f(&TraceContext {
+        process,
+        depth: if inner { depth + 1 } else { depth },
+        callers: &callers,
+        return_address: top.and_then(|frame| frame.ret_addr),
+        tail_call: top.is_some_and(|frame| frame.tail_call),
+        cfa: top.and_then(|frame| frame.cfa),
+    })
+}
+
+/// The addresses of the breakpoints which trace the calls to `func`
+fn breakpoints(func: &Function, prologue: bool) -> Vec<u64> {
+    let mut bps = vec![func.address];
+    bps.extend(prologue_breakpoint(func, prologue));
+    bps
+}
+
+/// The breakpoint at the end of the prologue of `func`, only needed when some of its
+/// parameters are stored relative to the frame base by the prologue
+fn prologue_breakpoint(func: &Function, prologue: bool) -> Option<u64> {
+    let frame_based = func
+        .parameters
+        .iter()
+        .flatten()
+        .any(|param| matches!(param.kind, FormalParameterKind::Memory(_)));
+    if prologue && frame_based && !func.inlined {
+        func.prologue_end_addr
+    } else {
+        None
+    }
+}
+
+/// Finds the functions in the binary using the given source
+fn resolve_functions(
+    source: FuncSource,
+    inline: bool,
+    binary: &Path,
+    obj_file: &object::File,
+) -> Result<Vec<Function>> {
+    let mut funcs = match source {
+        FuncSource::Heuristic => {
+            let funcs = get_functions(obj_file);
+            if funcs.is_empty() {
+                warn!("no function symbols found, is the binary stripped?");
+                discover_functions(obj_file)?
+            } else {
+                funcs
+            }
+        }
+        FuncSource::Dwarf => get_functions_dwarf(utf8_path(binary)?, obj_file)?,
+        FuncSource::Auto => get_functions_auto(utf8_path(binary)?, obj_file)?,
+    };
+    if inline {
+        funcs.extend(get_inlined_functions_dwarf(obj_file)?);
+    }
+    if obj_file.section_by_name(".debug_info").is_some() {
+        annotate_sources_dwarf(obj_file, &mut funcs)?;
+    }
+    Ok(funcs)
+}
+
+/// The debug info reader only takes UTF-8 paths
+fn utf8_path(path: &Path) -> Result<&str> {
+    path.to_str()
+        .ok_or_else(|| Error::NonUtf8Path(path.to_owned()))
+}
+
+/// Decides which calls get reported
+struct Output {
+    conditions: Vec<Condition>,
+    cond_subtree: bool,
+    max_depth: Option<usize>,
+    focus: Option<Regex>,
+    /// address of main with --from-main, and whether it passes the filters itself
+    main: Option<(u64, bool)>,
+}
+
+impl Output {
+    /// Whether only the subtrees of some calls are reported
+    fn restricted(&self) -> bool {
+        self.cond_subtree || self.focus.is_some() || self.main.is_some()
+    }
+
+    /// Whether a call entered under `parent` is inside a reported subtree, starting one itself
+    /// if it is a `root`, and its depth, counted from the outermost root of the subtree
+    fn nesting(&self, parent: Option<&Frame>, root: bool) -> (bool, usize) {
+        let inside = root || parent.is_some_and(|parent| parent.inside);
+        let depth = match parent {
+            Some(parent) if parent.inside || !self.restricted() => parent.depth + 1,
+            _ => 1,
+        };
+        (inside, depth)
+    }
+
+    /// Whether a call passing the filters gets reported at this nesting
+    fn shows(&self, inside: bool, depth: usize) -> bool {
+        (inside || !self.restricted()) && self.max_depth.is_none_or(|max| depth <= max)
+    }
+}
+
+/// A function which has been entered but not yet returned from
+struct Frame<'a> {
+    func: &'a Function,
+    /// where the call returns to, there is a breakpoint there
+    ret_addr: Option<u64>,
+    /// whether the call was reported, its return value is only reported if so
+    shown: bool,
+    /// whether the call is in a reported subtree, started by a --focus or --cond-subtree
+    /// match or by main with --from-main
+    inside: bool,
+    /// nesting depth, counted from the outermost root when only subtrees are reported
+    depth: usize,
+    /// set by --inject when the return value is replaced
+    fault: Option<Fault>,
+    /// canonical frame address of the call, unknown for inlined instances
+    cfa: Option<u64>,
+    /// whether the call was entered by a jump at the end of the call below it, which then
+    /// returns when this one does
+    tail_call: bool,
+}
+
+/// Where and how breakpoints get placed
+struct Placement {
+    /// with --lazy, and the functions it starts from
+    lazy: Option<(LazyPlacer, Vec<u64>)>,
+    /// addresses which get hardware breakpoints
+    hw: HashSet<u64>,
+    /// with --from-main, the breakpoints which are only placed while main runs
+    from_main: Option<FromMain>,
+}
+
+struct FromMain {
+    /// the breakpoints of main itself, which stay
+    main: Vec<u64>,
+    /// the breakpoints placed when main is entered
+    held: Vec<u64>,
+    /// the breakpoints placed since the start, removed when main returns
+    placed: Vec<u64>,
+    /// whether main is running
+    armed: bool,
+}
+
+impl Placement {
+    /// Sets the breakpoints, using hardware ones for the addresses in `hw`
+    fn place<E: DebuggerEngine>(
+        &mut self,
+        engine: &mut E,
+        process: &mut E::Process,
+        addresses: &[u64],
+    ) -> Result<()> {
+        let (hw_addrs, sw_addrs): (Vec<u64>, Vec<u64>) = addresses
+            .iter()
+            .partition(|address| self.hw.contains(address));
+        if let Some(from_main) = self.from_main.as_mut() {
+            from_main.placed.extend(addresses);
+        }
+        for address in hw_addrs {
+            engine.set_hw_breakpoint(process, address)?;
+        }
+        engine.set_breakpoints(process, &sw_addrs)
+    }
+
+    /// Places the breakpoints held back until main runs, once it is entered
+    fn arm<E: DebuggerEngine>(&mut self, engine: &mut E, process: &mut E::Process) -> Result<()> {
+        let held = match self.from_main.as_mut() {
+            Some(from_main) if !from_main.armed => {
+                from_main.armed = true;
+                from_main.held.clone()
+            }
+            _ => return Ok(()),
+        };
+        self.place(engine, process, &held)
+    }
+
+    /// Removes every breakpoint but those of main, once main returned
+    fn disarm<E: DebuggerEngine>(
+        &mut self,
+        engine: &mut E,
+        process: &mut E::Process,
+    ) -> Result<()> {
+        let from_main = match self.from_main.as_mut() {
+            Some(from_main) if from_main.armed => from_main,
+            _ => return Ok(()),
+        };
+        from_main.armed = false;
+        for address in std::mem::take(&mut from_main.placed) {
+            if !from_main.main.contains(&address) {
+                engine.remove_breakpoint(process, address)?;
+            }
+        }
+        // the callees get placed again if main is entered again
+        if let Some((placer, _)) = self.lazy.as_mut() {
+            placer.reset();
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn function(inlined: bool) -> Function {
+        Function {
+            address: 0x1000,
+            prologue_end_addr: None,
+            name: "f".to_string(),
+            parameters: vec![],
+            return_type: None,
+            inlined,
+            exit_addrs: vec![],
+            decl_file: None,
+            decl_line: None,
+            unit: None,
+        }
+    }
+
+    fn frame(
+        func: &Function,
+        ret_addr: Option<u64>,
+        cfa: Option<u64>,
+        tail_call: bool,
+    ) -> Frame<'_> {
+        Frame {
+            func,
+            ret_addr,
+            shown: true,
+            inside: false,
+            depth: 1,
+            fault: None,
+            cfa,
+            tail_call,
+        }
+    }
+
+    fn output(focus: Option<&str>, max_depth: Option<usize>) -> Output {
+        Output {
+            conditions: vec![],
+            cond_subtree: false,
+            max_depth,
+            focus: focus.map(|focus| Regex::new(focus).unwrap()),
+            main: None,
+        }
+    }
+
+    /// Enters a call under the top of `stack`, returning whether it is shown
+    fn enter<'a>(
+        output: &Output,
+        stack: &mut Vec<Frame<'a>>,
+        func: &'a Function,
+        root: bool,
+    ) -> bool {
+        let (inside, depth) = output.nesting(stack.last(), root);
+        let shown = output.shows(inside, depth);
+        stack.push(Frame {
+            shown,
+            inside,
+            depth,
+            ..frame(func, None, None, false)
+        });
+        shown
+    }
+
+    #[test]
+    fn depth_counts_from_the_outermost_call() {
+        let func = function(false);
+        let output = output(None, Some(2));
+        let mut stack = vec![];
+        assert!(enter(&output, &mut stack, &func, false));
+        assert!(enter(&output, &mut stack, &func, false));
+        assert!(!enter(&output, &mut stack, &func, false));
+        assert_eq!(stack.iter().map(|f| f.depth).collect::<Vec<_>>(), [1, 2, 3]);
+        stack.truncate(1);
+        assert!(enter(&output, &mut stack, &func, false));
+    }
+
+    #[test]
+    fn focus_depth_counts_from_the_outermost_root() {
+        let func = function(false);
+        let output = output(Some("f"), Some(2));
+        let mut stack = vec![];
+        // calls outside of a focused one are hidden and don't count
+        assert!(!enter(&output, &mut stack, &func, false));
+        assert!(!enter(&output, &mut stack, &func, false));
+        assert!(enter(&output, &mut stack, &func, true));
+        assert!(enter(&output, &mut stack, &func, false));
+        // a root nested in another one keeps counting from the outer one
+        assert!(!enter(&output, &mut stack, &func, true));
+        let depths: Vec<_> = stack.iter().map(|f| (f.inside, f.depth)).collect();
+        assert_eq!(
+            depths,
+            [(false, 1), (false, 1), (true, 1), (true, 2), (true, 3)]
+        );
+        // the subtree ends with its root
+        stack.truncate(2);
+        assert!(!enter(&output, &mut stack, &func, false));
+    }
+
+    #[test]
+    fn tail_calls_inherit_the_return_address_and_frame() {
+        let traced = function(false);
+        let stack = [frame(&traced, Some(0x2000), Some(0x7ff0), false)];
+        assert!(is_tail_call(&stack, Some(0x2000), 0x7ff0));
+        // a call made by the function has its own frame and returns into it
+        assert!(!is_tail_call(&stack, Some(0x1010), 0x7fe0));
+        assert!(!is_tail_call(&stack, Some(0x2000), 0x7fe0));
+        // nothing is known about calls without a return breakpoint
+        assert!(!is_tail_call(&stack, None, 0x7ff0));
+        assert!(!is_tail_call(&[], Some(0x2000), 0x7ff0));
+    }
+
+    #[test]
+    fn tail_calls_look_past_inlined_instances() {
+        let traced = function(false);
+        let inlined = function(true);
+        let stack = [
+            frame(&traced, Some(0x2000), Some(0x7ff0), false),
+            frame(&inlined, None, None, false),
+        ];
+        assert!(is_tail_call(&stack, Some(0x2000), 0x7ff0));
+    }
+
+    #[test]
+    fn returns_close_the_calls_replaced_by_tail_calls() {
+        let traced = function(false);
+        let inlined = function(true);
+        let plain = [
+            frame(&traced, Some(0x2000), Some(0x7ff0), false),
+            frame(&traced, Some(0x1010), Some(0x7fe0), false),
+        ];
+        assert_eq!(returning_frames(&plain), 1);
+        let tail = [
+            frame(&traced, Some(0x3000), Some(0x7ff8), false),
+            frame(&traced, Some(0x2000), Some(0x7ff0), false),
+            frame(&traced, Some(0x2000), Some(0x7ff0), true),
+            frame(&traced, Some(0x2000), Some(0x7ff0), true),
+        ];
+        assert_eq!(returning_frames(&tail), 3);
+        let early_return = [
+            frame(&traced, Some(0x2000), Some(0x7ff0), false),
+            frame(&inlined, None, None, false),
+            frame(&inlined, None, None, false),
+        ];
+        assert_eq!(returning_frames(&early_return), 3);
+        assert_eq!(returning_frames(&[]), 0);
+    }
+}
@@ -0,0 +1,111 @@
+//! Data watchpoints, reporting the writes to a variable
+//!
+//! Variables and addresses are given as in the object file, like `--addr`, and are moved by
+//! the load address of a relocatable binary.
+
+use object::{Object, ObjectSymbol, SymbolKind};
+
+use crate::cli::{WatchSpec, WatchTarget};
+use crate::defs::{DebuggerEngine, ProcessInfo, Result};
+use crate::error::Error;
+use crate::function::get_variable_dwarf;
+
+pub struct Watch {
+    /// the variable or address as given by the user
+    pub name: String,
+    address: u64,
+    len: u64,
+    /// the last value seen
+    value: Vec<u8>,
+}
+
+impl Watch {
+    /// Finds where the target of `spec` lives, in relocatable binaries it is moved by `base`
+    pub fn resolve(spec: &WatchSpec, obj: &object::File, base: u64) -> Result<Self> {
+        let (name, address, size) = match &spec.target {
+            WatchTarget::Address(address) => (format!("{:#x}", address), address + base, None),
+            WatchTarget::Variable(name) => {
+                let symbol = obj.symbols().find(|symbol| {
+                    symbol.kind() == SymbolKind::Data && symbol.name() == Ok(name.as_str())
+                });
+                let (address, size) = match symbol {
+                    Some(symbol) => (symbol.address(), Some(symbol.size())),
+                    // fields and statics without a symbol need the debug info
+                    None => get_variable_dwarf(obj, name)?
+                        .ok_or_else(|| Error::UnknownVariable(name.clone()))?,
+                };
+                (name.clone(), address + base, size)
+            }
+        };
+        let len = spec.len.or(size).filter(|&len| len > 0).unwrap_or(8);
+        Ok(Self {
+            name,
+            address,
+            len,
+            value: vec![],
+        })
+    }
+
+    /// Programs the watchpoint and remembers the current value
+    pub fn arm<E>(&mut self, engine: &mut E, process: &mut E::Process, reads: bool) -> Result<()>
+    where
+        E: DebuggerEngine,
+        E::Process: ProcessInfo,
+    {
+        engine.set_watchpoint(process, self.address, self.len, reads)?;
+        self.value = self.read(process)?;
+        Ok(())
+    }
+
+    pub fn covers(&self, address: u64) -> bool {
+        address >= self.address && address < self.address + self.len
+    }
+
+    /// Reads the value after a hit, returns the old and new values formatted
+    pub fn update<P: ProcessInfo>(&mut self, process: &P) -> Result<(String, String)> {
+        let value = self.read(process)?;
+        let old = std::mem::replace(&mut self.value, value);
+        Ok((format_bytes(&old), format_bytes(&self.value)))
+    }
+
+    fn read<P: ProcessInfo>(&self, process: &P) -> Result<Vec<u8>> {
+        let mut value = vec![0; self.len as usize];
+        process.read_many(&mut [(self.address, &mut value[..])])?;
+        Ok(value)
+    }
+}
+
+/// Values which fit a register are shown as integers, anything longer as hex bytes
+fn format_bytes(data: &[u8]) -> String {
+    if data.len() <= 8 {
+        let value = data
+            .iter()
+            .rev()
+            .fold(0u64, |value, &byte| value << 8 | byte as u64);
+        format!("{}", value)
+    } else {
+        data.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_values_as_little_endian_integers() {
+        assert_eq!(format_bytes(&[]), "0");
+        assert_eq!(format_bytes(&[42]), "42");
+        assert_eq!(format_bytes(&[0x34, 0x12]), "4660");
+        assert_eq!(format_bytes(&[0xff; 4]), "4294967295");
+        assert_eq!(format_bytes(&[0xff; 8]), u64::MAX.to_string());
+    }
+
+    #[test]
+    fn formats_longer_values_as_hex_bytes() {
+        assert_eq!(
+            format_bytes(&[0, 1, 2, 3, 4, 5, 6, 7, 0xab]),
+            "0001020304050607ab"
+        );
+    }
+}